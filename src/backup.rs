@@ -0,0 +1,119 @@
+//! Downloads that back the firmware of the device up first and restore it if they fail.
+
+use crate::{DfuCrossUsb, DownloadOptions, Error, UploadOptions, UsbTransport};
+use dfu_core::DfuProtocol;
+
+/// The outcome of [`DfuCrossUsb::download_with_backup`].
+#[derive(Debug)]
+pub struct BackupReport {
+    /// The DfuSe address of the backup, `None` on DFU 1.1 devices.
+    pub address: Option<u32>,
+    /// The firmware read from the device before the download, e.g. to be saved to a file.
+    pub backup: Vec<u8>,
+    /// The error the download or its verification failed with, `None` if it succeeded.
+    pub failure: Option<Error>,
+    /// The outcome of restoring the backup after a failure, `None` if it was not needed.
+    pub restore: Option<Result<(), Error>>,
+}
+
+impl BackupReport {
+    /// Whether the new firmware was downloaded.
+    pub fn succeeded(&self) -> bool {
+        self.failure.is_none()
+    }
+
+    /// Whether the device holds the backed up firmware again after a failed download.
+    pub fn restored(&self) -> bool {
+        matches!(self.restore, Some(Ok(())))
+    }
+}
+
+impl<T: UsbTransport + 'static> DfuCrossUsb<T> {
+    /// Back the firmware of the device up, download `firmware` like [`download`](Self::download)
+    /// and, if the download or its verification fails, recover the device with
+    /// [`prepare`](Self::prepare) and download the backup again.
+    ///
+    /// DFU 1.1 devices are backed up whole with DFU_UPLOAD. DfuSe devices are backed up from
+    /// the start of the first to the end of the last page the download erases, so that the
+    /// restore puts back every byte the download touched. The restored device leaves DFU mode
+    /// from the address the download would have started the firmware from, not from the
+    /// backup. The backup is kept in memory and returned in the [`BackupReport`].
+    ///
+    /// Fails without writing anything if the backup cannot be made, e.g. with
+    /// [`Error::UploadNotSupported`] for devices that cannot upload.
+    pub async fn download_with_backup(
+        &self,
+        firmware: &[u8],
+        options: &DownloadOptions,
+    ) -> Result<BackupReport, Error> {
+        if !self.descriptor.can_upload {
+            return Err(Error::UploadNotSupported);
+        }
+        if options.verify && !self.can_verify() {
+            return Err(Error::VerifyNotSupported);
+        }
+
+        let (address, leave, backup) = match &self.protocol {
+            DfuProtocol::Dfuse { address, .. } => {
+                let address = options.address.unwrap_or(*address);
+                let pages = self.dfuse_erase_pages(&[(address, firmware)])?;
+                let (Some(first), Some(last)) = (pages.first(), pages.last()) else {
+                    return Ok(BackupReport {
+                        address: None,
+                        backup: Vec::new(),
+                        failure: None,
+                        restore: None,
+                    });
+                };
+                let length = (last.address - first.address + last.size) as usize;
+                self.expect_idle().await?;
+                let backup = self.dfuse_read(first.address, length).await?;
+                (Some(first.address), Some(address), backup)
+            }
+            DfuProtocol::Dfu => {
+                let mut backup = Vec::new();
+                self.upload(&mut backup, &UploadOptions::new()).await?;
+                (None, None, backup)
+            }
+        };
+
+        let Err(failure) = self.download(firmware, options).await else {
+            return Ok(BackupReport {
+                address,
+                backup,
+                failure: None,
+                restore: None,
+            });
+        };
+        let restore = self.restore(address, leave, &backup, options).await;
+        Ok(BackupReport {
+            address,
+            backup,
+            failure: Some(failure),
+            restore: Some(restore),
+        })
+    }
+
+    /// Download `backup` again after [`prepare`](Self::prepare), at DfuSe `address`, leaving
+    /// DFU mode from `leave`.
+    async fn restore(
+        &self,
+        address: Option<u32>,
+        leave: Option<u32>,
+        backup: &[u8],
+        options: &DownloadOptions,
+    ) -> Result<(), Error> {
+        self.prepare().await?;
+        let Some(address) = address else {
+            return self.download(backup, options).await;
+        };
+        let images = [(address, backup)];
+        let pages = self.dfuse_erase_pages(&images)?;
+        self.begin_operation(backup.len());
+        let result = self
+            .dfuse_download_pages(&pages, &images, leave, options)
+            .await;
+        self.progress_tracker().end();
+        result
+    }
+}
@@ -0,0 +1,72 @@
+//! Cancelling downloads and uploads between two blocks.
+
+use crate::{
+    DFU_ABORT, DFU_CLRSTATUS, DFU_GETSTATUS, DFU_REQUEST_IN, DFU_REQUEST_OUT, Error, UsbTransport,
+    runtime,
+};
+use dfu_core::State;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Cancels the download or upload of a [`DfuCrossUsb`](crate::DfuCrossUsb), see
+/// [`DfuCrossUsb::cancel_handle`](crate::DfuCrossUsb::cancel_handle).
+///
+/// The block in flight is completed, the next DFU_DNLOAD or DFU_UPLOAD is not sent. The device
+/// is returned to dfuIDLE instead, with DFU_CLRSTATUS if it is in dfuERROR and DFU_ABORT
+/// otherwise, and the operation fails with [`Error::Aborted`]. A cancellation requested while
+/// no transfer is in progress is discarded when the next one starts.
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    /// Request the cancellation of the current transfer.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether a cancellation was requested and not yet carried out.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Clear a requested cancellation, returning whether there was one.
+    pub(crate) fn take(&self) -> bool {
+        self.0.swap(false, Ordering::SeqCst)
+    }
+
+    /// Discard a cancellation requested before the operation starting now.
+    pub(crate) fn reset(&self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Return a device whose transfer was cancelled to dfuIDLE, as far as it answers each request
+/// within `timeout`.
+pub(crate) async fn abort<T: UsbTransport>(
+    transport: &T,
+    interface_number: u16,
+    timeout: Duration,
+) -> Error {
+    let status = runtime::timeout(
+        timeout,
+        transport.control_in(DFU_REQUEST_IN, DFU_GETSTATUS, 0, interface_number, 6),
+    )
+    .await;
+    let request = match status {
+        Ok(Some(Ok(status)))
+            if status.get(4).map(|&state| State::from(state)) == Some(State::DfuError) =>
+        {
+            DFU_CLRSTATUS
+        }
+        _ => DFU_ABORT,
+    };
+    // The cancellation is reported even if the device does not answer, which the next
+    // operation will notice.
+    let _ = runtime::timeout(
+        timeout,
+        transport.control_out(DFU_REQUEST_OUT, request, 0, interface_number, &[]),
+    )
+    .await;
+    Error::Aborted
+}
@@ -0,0 +1,50 @@
+//! CRC-32 as used by the DFU file suffix (DFU 1.1 Specification, Appendix B), which is the
+//! CRC-32 of zlib and Ethernet.
+
+const POLYNOMIAL: u32 = 0xedb8_8320;
+
+const TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < table.len() {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// The CRC-32 of `data`.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data.iter().copied());
+    crc.finish()
+}
+
+/// A CRC-32 computed over data fed piece by piece.
+pub(crate) struct Crc32(u32);
+
+impl Crc32 {
+    pub(crate) fn new() -> Self {
+        Self(!0)
+    }
+
+    pub(crate) fn update(&mut self, data: impl IntoIterator<Item = u8>) {
+        self.0 = data.into_iter().fold(self.0, |crc, byte| {
+            TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+        });
+    }
+
+    pub(crate) fn finish(&self) -> u32 {
+        !self.0
+    }
+}
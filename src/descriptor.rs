@@ -0,0 +1,133 @@
+//! Walking configuration descriptors.
+//!
+//! A configuration descriptor is followed by its interface descriptors, each followed by the
+//! class specific and endpoint descriptors that belong to it. The DFU functional descriptor
+//! (DFU 1.1 Specification, Section 4.2.4) follows the interface descriptor of a DFU interface,
+//! most bootloaders only emit it once after the last alternate setting of the interface.
+
+use crate::firmware::DeviceIds;
+use usb::descriptor_type;
+
+/// bInterfaceSubClass of a DFU interface (DFU 1.1 Specification, Section 4.2.1).
+pub(crate) const DFU_SUBCLASS: u8 = 0x01;
+/// bInterfaceProtocol of a DFU interface in run-time mode.
+pub(crate) const RUNTIME_PROTOCOL: u8 = 0x01;
+/// bInterfaceProtocol of a DFU interface in DFU mode.
+pub(crate) const DFU_MODE_PROTOCOL: u8 = 0x02;
+
+/// The fields of a standard interface descriptor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct InterfaceDescriptor {
+    pub interface_number: u8,
+    pub alternate_setting: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub interface_string: u8,
+}
+
+/// An interface alternate setting and the raw DFU functional descriptor of its interface.
+pub(crate) struct Interface<'a> {
+    pub descriptor: InterfaceDescriptor,
+    pub functional_descriptor: Option<&'a [u8]>,
+}
+
+/// Split a descriptor buffer into descriptors by their bLength.
+fn descriptors(mut bytes: &[u8]) -> impl Iterator<Item = &[u8]> {
+    std::iter::from_fn(move || {
+        let len = *bytes.first()? as usize;
+        if len < 2 || len > bytes.len() {
+            return None;
+        }
+        let (descriptor, rest) = bytes.split_at(len);
+        bytes = rest;
+        Some(descriptor)
+    })
+}
+
+/// List every interface alternate setting of a configuration descriptor.
+pub(crate) fn interfaces(configuration: &[u8]) -> Vec<Interface<'_>> {
+    let mut interfaces: Vec<Interface<'_>> = Vec::new();
+    for descriptor in descriptors(configuration) {
+        match descriptor[1] {
+            descriptor_type::INTERFACE if descriptor.len() >= 9 => {
+                interfaces.push(Interface {
+                    descriptor: InterfaceDescriptor {
+                        interface_number: descriptor[2],
+                        alternate_setting: descriptor[3],
+                        class: descriptor[5],
+                        subclass: descriptor[6],
+                        protocol: descriptor[7],
+                        interface_string: descriptor[8],
+                    },
+                    functional_descriptor: None,
+                });
+            }
+            crate::DFU_FUNCTIONAL_DESCRIPTOR_TYPE => {
+                // The functional descriptor applies to every alternate setting of the interface
+                // it follows.
+                let Some(number) = interfaces.last().map(|i| i.descriptor.interface_number) else {
+                    continue;
+                };
+                for interface in interfaces
+                    .iter_mut()
+                    .rev()
+                    .take_while(|i| i.descriptor.interface_number == number)
+                {
+                    interface.functional_descriptor.get_or_insert(descriptor);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Alternate settings listed after the functional descriptor share it as well.
+    for i in 1..interfaces.len() {
+        let (before, after) = interfaces.split_at_mut(i);
+        let (previous, interface) = (&before[i - 1], &mut after[0]);
+        if interface.functional_descriptor.is_none()
+            && previous.descriptor.interface_number == interface.descriptor.interface_number
+        {
+            interface.functional_descriptor = previous.functional_descriptor;
+        }
+    }
+
+    interfaces
+}
+
+/// The bConfigurationValue of a configuration descriptor.
+pub(crate) fn configuration_value(configuration: &[u8]) -> Option<u8> {
+    configuration.get(5).copied()
+}
+
+/// The bNumConfigurations of a device descriptor.
+pub(crate) fn num_configurations(device: &[u8]) -> Option<u8> {
+    device.get(17).copied()
+}
+
+/// The idVendor, idProduct and bcdDevice of a device descriptor.
+pub(crate) fn device_ids(device: &[u8]) -> Option<DeviceIds> {
+    let field = |i: usize| Some(u16::from_le_bytes([*device.get(i)?, *device.get(i + 1)?]));
+    Some(DeviceIds {
+        vendor_id: field(8)?,
+        product_id: field(10)?,
+        bcd_device: field(12)?,
+    })
+}
+
+/// The iSerialNumber of a device descriptor, if the device has a serial number.
+pub(crate) fn serial_number_index(device: &[u8]) -> Option<u8> {
+    device.get(16).copied().filter(|&index| index != 0)
+}
+
+/// Find an interface alternate setting in a configuration descriptor.
+pub(crate) fn find_interface(
+    configuration: &[u8],
+    interface_number: u8,
+    alternate_setting: u8,
+) -> Option<Interface<'_>> {
+    interfaces(configuration).into_iter().find(|i| {
+        i.descriptor.interface_number == interface_number
+            && i.descriptor.alternate_setting == alternate_setting
+    })
+}
@@ -0,0 +1,378 @@
+//! Switching run-time mode devices to DFU mode.
+//!
+//! A device in run-time mode (bInterfaceProtocol 1) is switched to DFU mode with DFU_DETACH,
+//! followed by a USB reset unless it has bitWillDetach set (DFU 1.1 Specification, Section 5).
+//! It then re-enumerates in DFU mode, usually with a different product id.
+
+use crate::descriptor;
+use crate::discover::{DfuMode, discover, discover_transport};
+use crate::runtime;
+use crate::{CrossUsbTransport, DFU_DETACH, DFU_REQUEST_OUT, DfuCrossUsb, Error, UsbTransport};
+use cross_usb::usb::UsbDevice;
+use std::time::Duration;
+use usb::descriptor_type;
+
+/// How often to look for the re-enumerated device.
+const REENUMERATION_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a device may take to re-enumerate after it detached.
+const REENUMERATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl<T: UsbTransport + 'static> DfuCrossUsb<T> {
+    /// Ask a run-time mode device to switch to DFU mode.
+    ///
+    /// DFU_DETACH is sent with the wDetachTimeOut of the functional descriptor. Devices without
+    /// bitWillDetach wait for the host to reset them, so they are reset right away.
+    pub async fn detach(&self) -> Result<(), Error> {
+        let detached = self
+            .write_control(
+                DFU_REQUEST_OUT,
+                DFU_DETACH,
+                self.descriptor.detach_timeout,
+                &[],
+            )
+            .await;
+        if self.descriptor.will_detach {
+            // The device may leave the bus before it acknowledges the request.
+            return Ok(());
+        }
+        detached?;
+        self.usb_reset().await
+    }
+
+    /// Detach a run-time mode device and open its DFU mode interface once it re-enumerated.
+    ///
+    /// `reopen` is polled until it returns the transport of the re-enumerated device, for at
+    /// most a few seconds plus wDetachTimeOut if the device detaches itself. An error returned
+    /// by `reopen`, e.g. because several devices match, stops the polling. The first DFU mode
+    /// interface of that device is opened with `alternative_setting`.
+    pub async fn detach_and_reopen_with<F, Fut>(
+        self,
+        alternative_setting: u8,
+        mut reopen: F,
+    ) -> Result<Self, Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<Option<T>, Error>>,
+    {
+        let mut timeout = REENUMERATION_TIMEOUT;
+        if self.descriptor.will_detach {
+            timeout += Duration::from_millis(self.descriptor.detach_timeout as u64);
+        }
+
+        self.detach().await?;
+        // Release the run-time mode device, it is about to disappear.
+        drop(self);
+
+        for _ in 0..timeout.as_millis() / REENUMERATION_POLL_INTERVAL.as_millis() {
+            runtime::sleep(REENUMERATION_POLL_INTERVAL).await?;
+            let Some(transport) = reopen().await? else {
+                continue;
+            };
+            let interfaces = discover_transport(&transport).await?;
+            if let Some(interface) = interfaces.iter().find(|i| i.mode == DfuMode::Dfu) {
+                return Self::from_transport(
+                    transport,
+                    interface.interface_number,
+                    alternative_setting,
+                )
+                .await;
+            }
+        }
+
+        Err(Error::DeviceNotFound)
+    }
+}
+
+impl DfuCrossUsb<CrossUsbTransport> {
+    /// Detach a run-time mode device and open it again once it re-enumerated in DFU mode.
+    ///
+    /// The re-enumerated device is recognised by its vendor id, its serial number if it has one
+    /// and, on native targets, the hub port it is plugged into, before it is opened. Fails with
+    /// [`Error::MultipleDevicesFound`] if several devices match. On WASM the DFU mode device
+    /// must have been paired with the page before, as no permission prompt can be shown at
+    /// this point.
+    pub async fn detach_and_reopen(self, alternative_setting: u8) -> Result<Self, Error> {
+        let serial_number = read_serial_number(&*self.transport).await;
+        let identity = DeviceIdentity {
+            vendor_id: self.transport.device().vendor_id().await,
+            #[cfg(not(target_family = "wasm"))]
+            port_path: native::port_path(self.transport.device(), serial_number.as_deref()).await,
+            serial_number,
+        };
+
+        self.detach_and_reopen_with(alternative_setting, || find_dfu_mode_device(&identity))
+            .await
+    }
+}
+
+/// What recognises a device once it re-enumerated in DFU mode.
+struct DeviceIdentity {
+    vendor_id: u16,
+    serial_number: Option<String>,
+    /// The bus and the hub ports the device is plugged into, if it could be determined.
+    #[cfg(not(target_family = "wasm"))]
+    port_path: Option<String>,
+}
+
+async fn read_serial_number<T: UsbTransport>(transport: &T) -> Option<String> {
+    let device = transport
+        .descriptor(descriptor_type::DEVICE, 0, 0, 18)
+        .await
+        .ok()?;
+    let index = descriptor::serial_number_index(&device)?;
+    transport.string_descriptor(index).await.ok()
+}
+
+/// Open the device matching `identity` that has a DFU mode interface, claiming that interface.
+///
+/// The devices are matched on what the operating system reports about them, as `cross_usb`
+/// resets a native device when a handle to it is closed, so that other devices on the bus are
+/// never opened.
+#[cfg(not(target_family = "wasm"))]
+async fn find_dfu_mode_device(
+    identity: &DeviceIdentity,
+) -> Result<Option<CrossUsbTransport>, Error> {
+    let Ok(devices) = nusb::list_devices() else {
+        return Ok(None);
+    };
+    let matches: Vec<_> = devices
+        .filter(|device| native::matches(identity, device))
+        .collect();
+    let device = match matches.as_slice() {
+        [] => return Ok(None),
+        [device] => device,
+        _ => return Err(Error::MultipleDevicesFound),
+    };
+    let filter = cross_usb::DeviceFilter {
+        vendor_id: Some(device.vendor_id()),
+        product_id: Some(device.product_id()),
+        ..Default::default()
+    };
+    let Ok(list) = cross_usb::get_device_list(vec![filter]).await else {
+        return Ok(None);
+    };
+    let descriptors = native::Descriptors::of(device);
+    let mut candidates = Vec::new();
+    for candidate in list {
+        if native::Descriptors::of_info(&candidate).await == descriptors {
+            candidates.push(candidate);
+        }
+    }
+    // Identical devices only differ in what cross_usb does not report, e.g. serial numbers.
+    let device_info = match candidates.len() {
+        1 => candidates.pop(),
+        _ => candidates
+            .into_iter()
+            .find(|candidate| native::describes(candidate, device)),
+    };
+    match device_info {
+        Some(device_info) => open_dfu_interface(device_info).await,
+        None => Ok(None),
+    }
+}
+
+/// Open the paired device matching `identity` that has a DFU mode interface, claiming that
+/// interface.
+///
+/// WebUSB does not report serial numbers before a device is opened, which does not reset it.
+#[cfg(target_family = "wasm")]
+async fn find_dfu_mode_device(
+    identity: &DeviceIdentity,
+) -> Result<Option<CrossUsbTransport>, Error> {
+    let filter = cross_usb::DeviceFilter {
+        vendor_id: Some(identity.vendor_id),
+        ..Default::default()
+    };
+    let Ok(candidates) = cross_usb::get_device_list(vec![filter]).await else {
+        return Ok(None);
+    };
+    let mut found = None;
+    for device_info in candidates {
+        let Ok(Some(transport)) = open_dfu_interface(device_info).await else {
+            continue;
+        };
+        if identity.serial_number.is_some()
+            && read_serial_number(&transport).await != identity.serial_number
+        {
+            continue;
+        }
+        if found.replace(transport).is_some() {
+            return Err(Error::MultipleDevicesFound);
+        }
+    }
+    Ok(found)
+}
+
+/// Open a device and claim its first DFU mode interface, `None` if it has none.
+async fn open_dfu_interface(
+    device_info: cross_usb::DeviceInfo,
+) -> Result<Option<CrossUsbTransport>, Error> {
+    let device = discover(device_info).await?;
+    let dfu_mode = device.interfaces().iter().any(|i| i.mode == DfuMode::Dfu);
+    Ok(dfu_mode.then(|| device.into_transport()))
+}
+
+/// Matching `cross_usb` devices with what `nusb`, which backs it on native targets, reports
+/// about them without opening them.
+#[cfg(not(target_family = "wasm"))]
+mod native {
+    use super::DeviceIdentity;
+    use crate::descriptor::{DFU_MODE_PROTOCOL, DFU_SUBCLASS};
+    use cross_usb::usb::{UsbDevice, UsbDeviceInfo};
+
+    /// What both `nusb` and `cross_usb` report about a device without opening it.
+    #[derive(Debug, PartialEq, Eq)]
+    pub(super) struct Descriptors {
+        vendor_id: u16,
+        product_id: u16,
+        class: u8,
+        subclass: u8,
+        manufacturer: Option<String>,
+        product: Option<String>,
+    }
+
+    impl Descriptors {
+        pub(super) fn of(device: &nusb::DeviceInfo) -> Self {
+            Self {
+                vendor_id: device.vendor_id(),
+                product_id: device.product_id(),
+                class: device.class(),
+                subclass: device.subclass(),
+                manufacturer: device.manufacturer_string().map(str::to_string),
+                product: device.product_string().map(str::to_string),
+            }
+        }
+
+        pub(super) async fn of_info(device: &cross_usb::DeviceInfo) -> Self {
+            Self {
+                vendor_id: device.vendor_id().await,
+                product_id: device.product_id().await,
+                class: device.class().await,
+                subclass: device.subclass().await,
+                manufacturer: device.manufacturer_string().await,
+                product: device.product_string().await,
+            }
+        }
+
+        async fn of_device(device: &cross_usb::Device) -> Self {
+            Self {
+                vendor_id: device.vendor_id().await,
+                product_id: device.product_id().await,
+                class: device.class().await,
+                subclass: device.subclass().await,
+                manufacturer: device.manufacturer_string().await,
+                product: device.product_string().await,
+            }
+        }
+    }
+
+    /// Whether `device` is the DFU mode device of `identity`. Devices whose interfaces are not
+    /// reported, e.g. those bound to a single driver on Windows, may be in DFU mode.
+    pub(super) fn matches(identity: &DeviceIdentity, device: &nusb::DeviceInfo) -> bool {
+        let mut interfaces = device.interfaces().peekable();
+        let dfu_mode = interfaces.peek().is_none()
+            || interfaces.any(|interface| {
+                interface.class() == usb::class_code::APPLICATION
+                    && interface.subclass() == DFU_SUBCLASS
+                    && interface.protocol() == DFU_MODE_PROTOCOL
+            });
+        device.vendor_id() == identity.vendor_id
+            && dfu_mode
+            && (identity.serial_number.is_none()
+                || device.serial_number() == identity.serial_number.as_deref())
+            && (identity.port_path.is_none() || device_port_path(device) == identity.port_path)
+    }
+
+    /// Whether a `cross_usb` device or device info describes `device`, for telling apart
+    /// devices whose [`Descriptors`] are identical.
+    ///
+    /// `cross_usb` 0.4 neither reports serial numbers and bus addresses nor gives access to the
+    /// `nusb` device it wraps, but its `Debug` output embeds the `Debug` output of that device.
+    /// This is not a stable API of either crate, `debug_output_embeds_nusb_device` checks it.
+    pub(super) fn describes(candidate: &impl std::fmt::Debug, device: &nusb::DeviceInfo) -> bool {
+        format!("{candidate:?}").contains(&format!("{device:?}"))
+    }
+
+    /// The port path of an open `cross_usb` device with serial number `serial_number`.
+    pub(super) async fn port_path(
+        device: &cross_usb::Device,
+        serial_number: Option<&str>,
+    ) -> Option<String> {
+        let descriptors = Descriptors::of_device(device).await;
+        let mut candidates: Vec<_> = nusb::list_devices()
+            .ok()?
+            .filter(|info| Descriptors::of(info) == descriptors)
+            .filter(|info| serial_number.is_none() || info.serial_number() == serial_number)
+            .collect();
+        let info = match candidates.len() {
+            1 => candidates.pop()?,
+            _ => candidates
+                .into_iter()
+                .find(|info| describes(device, info))?,
+        };
+        device_port_path(&info)
+    }
+
+    /// The bus and the hub ports a device is plugged into, which do not change when it
+    /// re-enumerates.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn device_port_path(device: &nusb::DeviceInfo) -> Option<String> {
+        // The sysfs name of a device is its port path, e.g. "1-2.4".
+        Some(
+            device
+                .sysfs_path()
+                .file_name()?
+                .to_string_lossy()
+                .into_owned(),
+        )
+    }
+
+    #[cfg(target_os = "macos")]
+    fn device_port_path(device: &nusb::DeviceInfo) -> Option<String> {
+        // The location id holds the bus and one nibble per hub port.
+        Some(format!("{:08x}", device.location_id()))
+    }
+
+    #[cfg(target_os = "windows")]
+    fn device_port_path(device: &nusb::DeviceInfo) -> Option<String> {
+        Some(format!(
+            "{}#{}",
+            device.parent_instance_id().to_string_lossy(),
+            device.port_number()
+        ))
+    }
+
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "windows"
+    )))]
+    fn device_port_path(_device: &nusb::DeviceInfo) -> Option<String> {
+        None
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use futures::executor::block_on;
+
+        /// Fails if `cross_usb` stops embedding the `Debug` output of the `nusb` device in its
+        /// own, which [`describes`] relies on. Needs at least one USB device to check anything.
+        #[test]
+        fn debug_output_embeds_nusb_device() {
+            let Ok(devices) = nusb::list_devices() else {
+                return;
+            };
+            for device in devices {
+                let filter = cross_usb::DeviceFilter {
+                    vendor_id: Some(device.vendor_id()),
+                    product_id: Some(device.product_id()),
+                    ..Default::default()
+                };
+                let mut candidates = block_on(cross_usb::get_device_list(vec![filter])).unwrap();
+                assert!(candidates.any(|candidate| describes(&candidate, &device)));
+            }
+        }
+    }
+}
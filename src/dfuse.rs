@@ -0,0 +1,108 @@
+//! The DfuSe commands of STM32 bootloaders (UM0391, Section 6.1), sent as DFU_DNLOAD block 0.
+
+use crate::request::{DFUSE_ERASE, DFUSE_READ_UNPROTECT, DFUSE_SET_ADDRESS};
+use crate::{DfuCrossUsb, Error, UsbTransport};
+use std::fmt;
+
+/// A DfuSe command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DfuseCommand {
+    /// Set the address pointer used by the following DFU_DNLOAD and DFU_UPLOAD blocks.
+    SetAddress(u32),
+    /// Erase the page containing an address.
+    Erase(u32),
+    /// Erase the whole memory.
+    MassErase,
+    /// Remove the read protection, which erases the whole memory.
+    ReadUnprotect,
+}
+
+impl DfuseCommand {
+    /// The data of the DFU_DNLOAD block 0 sending the command.
+    pub(crate) fn payload(&self) -> Vec<u8> {
+        match *self {
+            Self::SetAddress(address) => {
+                [&[DFUSE_SET_ADDRESS][..], &address.to_le_bytes()].concat()
+            }
+            Self::Erase(address) => [&[DFUSE_ERASE][..], &address.to_le_bytes()].concat(),
+            Self::MassErase => vec![DFUSE_ERASE],
+            Self::ReadUnprotect => vec![DFUSE_READ_UNPROTECT],
+        }
+    }
+}
+
+impl fmt::Display for DfuseCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SetAddress(address) => write!(f, "set address {address:#010x}"),
+            Self::Erase(address) => write!(f, "erase of page {address:#010x}"),
+            Self::MassErase => f.write_str("mass erase"),
+            Self::ReadUnprotect => f.write_str("read unprotect"),
+        }
+    }
+}
+
+/// The DfuSe commands of a [`DfuCrossUsb`], see [`DfuCrossUsb::dfuse`].
+///
+/// Each command waits until the device executed it and leaves it in dfuDNLOAD-IDLE, ready for
+/// the next command or DFU_DNLOAD block. A command the device fails fails with
+/// [`Error::DfuseCommand`].
+pub struct Dfuse<'a, T: UsbTransport + 'static> {
+    device: &'a DfuCrossUsb<T>,
+}
+
+impl<T: UsbTransport + 'static> Dfuse<'_, T> {
+    /// Set the address pointer.
+    pub async fn set_address(&self, address: u32) -> Result<(), Error> {
+        self.device
+            .dfuse_command(DfuseCommand::SetAddress(address))
+            .await
+    }
+
+    /// Erase the page containing `address`.
+    pub async fn erase_page(&self, address: u32) -> Result<(), Error> {
+        self.device
+            .dfuse_command(DfuseCommand::Erase(address))
+            .await
+    }
+
+    /// Erase the whole memory of the alternate setting.
+    pub async fn mass_erase(&self) -> Result<(), Error> {
+        self.device.dfuse_command(DfuseCommand::MassErase).await
+    }
+
+    /// Remove the read protection of the device, erasing its whole memory.
+    ///
+    /// STM32 devices reset once the memory is erased and may not report the status of the
+    /// command, which is not treated as a failure once the device accepted the command. They
+    /// have to be opened again.
+    pub async fn read_unprotect(&self) -> Result<(), Error> {
+        let command = DfuseCommand::ReadUnprotect;
+        self.device.dnload_block(0, &command.payload()).await?;
+        match self.device.wait_for_command(command).await {
+            Err(Error::WebUsb(_)) => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Leave DFU mode, starting the firmware at `address`.
+    pub async fn leave(&self, address: u32) -> Result<(), Error> {
+        self.device.dfuse_leave(address).await
+    }
+
+    /// Return to dfuIDLE after commands, e.g. before reading memory with DFU_UPLOAD.
+    pub async fn abort(&self) -> Result<(), Error> {
+        self.device.abort().await
+    }
+}
+
+impl<T: UsbTransport + 'static> DfuCrossUsb<T> {
+    /// The DfuSe commands of the device, failing with [`Error::DfuseNotSupported`] unless the
+    /// alternate setting is a DfuSe memory.
+    pub fn dfuse(&self) -> Result<Dfuse<'_, T>, Error> {
+        if !self.is_dfuse() {
+            return Err(Error::DfuseNotSupported);
+        }
+        Ok(Dfuse { device: self })
+    }
+}
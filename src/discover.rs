@@ -0,0 +1,142 @@
+//! Discovery of the DFU interfaces of a device.
+//!
+//! [`discover`] lists every DFU interface alternate setting of a device, the information
+//! `dfu-util -l` shows, so that a target can be picked before calling
+//! [`DfuCrossUsb::open`](crate::DfuCrossUsb::open).
+
+use crate::descriptor::{self, DFU_MODE_PROTOCOL, DFU_SUBCLASS, RUNTIME_PROTOCOL};
+use crate::{CrossUsbTransport, DfuCrossUsb, Error, UsbTransport};
+use dfu_core::functional_descriptor::FunctionalDescriptor;
+use usb::descriptor_type;
+
+/// The mode of a DFU interface (DFU 1.1 Specification, Section 4.2.1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DfuMode {
+    /// Run-time mode (bInterfaceProtocol 1), the device must be detached before a download.
+    Runtime,
+    /// DFU mode (bInterfaceProtocol 2), the device is ready for a download or upload.
+    Dfu,
+}
+
+/// A DFU interface alternate setting.
+#[derive(Debug, Clone)]
+pub struct DfuInterface {
+    /// bConfigurationValue of the configuration containing the interface.
+    pub configuration: u8,
+    /// bInterfaceNumber.
+    pub interface_number: u8,
+    /// bAlternateSetting.
+    pub alternate_setting: u8,
+    /// Run-time or DFU mode.
+    pub mode: DfuMode,
+    /// The iInterface string, e.g. "@Internal Flash /0x08000000/04*016Kg" on DfuSe devices.
+    pub name: Option<String>,
+    /// The DFU functional descriptor of the interface, if the device provides a valid one.
+    pub functional_descriptor: Option<FunctionalDescriptor>,
+}
+
+/// A device whose DFU interfaces were listed by [`discover`].
+///
+/// The device is kept open, so that the interface picked from
+/// [`interfaces`](Self::interfaces) is opened without opening the device again. On native
+/// targets `cross_usb` resets a device when it is closed, which happens when this is dropped
+/// without being opened.
+pub struct DiscoveredDevice {
+    transport: CrossUsbTransport,
+    interfaces: Vec<DfuInterface>,
+}
+
+impl DiscoveredDevice {
+    /// The DFU interface alternate settings of the device.
+    pub fn interfaces(&self) -> &[DfuInterface] {
+        &self.interfaces
+    }
+
+    /// Open an interface alternate setting for DFU, like [`DfuCrossUsb::open`].
+    pub async fn open(
+        mut self,
+        interface_number: u8,
+        alternative_setting: u8,
+    ) -> Result<DfuCrossUsb, Error> {
+        if self.transport.interface_number() != interface_number {
+            self.transport.claim_interface(interface_number).await?;
+        }
+        DfuCrossUsb::from_transport(self.transport, interface_number, alternative_setting).await
+    }
+
+    /// The open device, with the first DFU interface claimed.
+    pub(crate) fn into_transport(self) -> CrossUsbTransport {
+        self.transport
+    }
+}
+
+/// List the DFU interfaces of a `cross_usb` device.
+///
+/// The descriptors are read through the first interface that can be claimed, as the interfaces
+/// of a composite run-time mode device may be held by kernel drivers. The first DFU interface
+/// is claimed instead once it is known.
+pub async fn discover(device_info: cross_usb::DeviceInfo) -> Result<DiscoveredDevice, Error> {
+    let mut transport = CrossUsbTransport::open_any(device_info).await?;
+    let interfaces = discover_transport(&transport).await?;
+    if let Some(interface) = interfaces.first()
+        && interface.interface_number != transport.interface_number()
+    {
+        // The interface read through is still claimed if the DFU interface cannot be.
+        let _ = transport.claim_interface(interface.interface_number).await;
+    }
+    Ok(DiscoveredDevice {
+        transport,
+        interfaces,
+    })
+}
+
+/// List the DFU interfaces of the device behind a [`UsbTransport`].
+pub async fn discover_transport<T: UsbTransport>(
+    transport: &T,
+) -> Result<Vec<DfuInterface>, Error> {
+    let device = transport
+        .descriptor(descriptor_type::DEVICE, 0, 0, 18)
+        .await?;
+    let num_configurations =
+        descriptor::num_configurations(&device).ok_or(Error::ConfigurationDescriptorNotFound)?;
+
+    let mut dfu_interfaces = Vec::new();
+    for index in 0..num_configurations {
+        let configuration = transport.configuration_descriptor(index).await?;
+        let configuration_value = descriptor::configuration_value(&configuration)
+            .ok_or(Error::ConfigurationDescriptorNotFound)?;
+
+        for interface in descriptor::interfaces(&configuration) {
+            let descriptor = interface.descriptor;
+            if descriptor.class != usb::class_code::APPLICATION
+                || descriptor.subclass != DFU_SUBCLASS
+            {
+                continue;
+            }
+            let mode = match descriptor.protocol {
+                RUNTIME_PROTOCOL => DfuMode::Runtime,
+                DFU_MODE_PROTOCOL => DfuMode::Dfu,
+                _ => continue,
+            };
+            let name = match descriptor.interface_string {
+                0 => None,
+                index => transport.string_descriptor(index).await.ok(),
+            };
+            let functional_descriptor = interface
+                .functional_descriptor
+                .and_then(FunctionalDescriptor::from_bytes)
+                .and_then(Result::ok);
+
+            dfu_interfaces.push(DfuInterface {
+                configuration: configuration_value,
+                interface_number: descriptor.interface_number,
+                alternate_setting: descriptor.alternate_setting,
+                mode,
+                name,
+                functional_descriptor,
+            });
+        }
+    }
+
+    Ok(dfu_interfaces)
+}
@@ -0,0 +1,305 @@
+//! Downloads driven by this crate rather than by `dfu_core`, so that the firmware can be read
+//! back before the device leaves DFU mode.
+
+use crate::firmware::GapFill;
+use crate::plan::ErasePage;
+use crate::request::DFUSE_FIRST_BLOCK;
+use crate::{DfuCrossUsb, DfuseCommand, Error, UsbTransport};
+use dfu_core::{DfuProtocol, State};
+
+/// Options of [`DfuCrossUsb::download`].
+#[derive(Debug, Clone, Default)]
+pub struct DownloadOptions {
+    pub(crate) verify: bool,
+    pub(crate) address: Option<u32>,
+    pub(crate) gap_fill: GapFill,
+}
+
+impl DownloadOptions {
+    /// Options of a plain download without verification.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read the firmware back after writing it and compare it with the image.
+    pub fn with_verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Write a DfuSe device at `address` rather than at the start of the memory region of the
+    /// alternate setting. DFU 1.1 devices have no addresses and ignore it.
+    pub fn with_address(mut self, address: u32) -> Self {
+        self.address = Some(address);
+        self
+    }
+
+    /// How [`DfuCrossUsb::download_sparse`] joins the segments of sparse firmware for DFU 1.1
+    /// devices, which have no addresses. Gaps are refused by default.
+    pub fn with_gap_fill(mut self, gap_fill: GapFill) -> Self {
+        self.gap_fill = gap_fill;
+        self
+    }
+}
+
+impl<T: UsbTransport + 'static> DfuCrossUsb<T> {
+    /// Download `firmware` and manifest it.
+    ///
+    /// With [`DownloadOptions::with_verify`] the firmware is read back with DFU_UPLOAD and a
+    /// difference is reported as [`Error::VerifyMismatch`]. DfuSe devices are verified before
+    /// they leave DFU mode. DFU 1.1 cannot read back a download before it is manifested, so
+    /// other devices are verified after manifestation, which requires bitManifestationTolerant.
+    /// Devices that cannot be verified are refused with [`Error::VerifyNotSupported`] before
+    /// anything is written.
+    pub async fn download(&self, firmware: &[u8], options: &DownloadOptions) -> Result<(), Error> {
+        if options.verify && !self.can_verify() {
+            return Err(Error::VerifyNotSupported);
+        }
+        if firmware.is_empty() {
+            return Ok(());
+        }
+
+        self.begin_operation(firmware.len());
+        let result = self.download_image(firmware, options).await;
+        self.progress_tracker().end();
+        result
+    }
+
+    async fn download_image(
+        &self,
+        firmware: &[u8],
+        options: &DownloadOptions,
+    ) -> Result<(), Error> {
+        match &self.protocol {
+            DfuProtocol::Dfuse { address, .. } => {
+                let address = options.address.unwrap_or(*address);
+                self.dfuse_download(&[(address, firmware)], options).await
+            }
+            DfuProtocol::Dfu => {
+                self.expect_idle().await?;
+                let block_num = self.write_blocks(firmware).await?;
+                self.manifest(block_num).await?;
+                if options.verify {
+                    let read_back = self.read_back(0, firmware.len()).await?;
+                    compare(firmware, &read_back, None)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub(crate) fn can_verify(&self) -> bool {
+        self.descriptor.can_upload && (self.is_dfuse() || self.descriptor.manifestation_tolerant)
+    }
+
+    /// Write `firmware` with DFU_DNLOAD blocks starting at block 0, returning the next block
+    /// number.
+    async fn write_blocks(&self, firmware: &[u8]) -> Result<u16, Error> {
+        let mut block_num = 0u16;
+        for chunk in firmware.chunks(self.descriptor.transfer_size as usize) {
+            self.dnload_block(block_num, chunk).await?;
+            self.wait_for_state(State::DfuDnloadIdle).await?;
+            // The block number wraps around on long downloads (Section 6.1.1).
+            block_num = block_num.wrapping_add(1);
+        }
+        Ok(block_num)
+    }
+
+    /// End a download with a zero length DFU_DNLOAD (DFU 1.1 Specification, Section 7.2).
+    async fn manifest(&self, block_num: u16) -> Result<(), Error> {
+        self.dnload_block(block_num, &[]).await?;
+        if self.descriptor.manifestation_tolerant {
+            self.wait_for_state(State::DfuIdle).await?;
+            return Ok(());
+        }
+
+        // The device may not answer while it manifests, after which it waits for a reset.
+        let _ = self.get_status().await;
+        if !self.descriptor.will_detach {
+            self.usb_reset().await?;
+        }
+        Ok(())
+    }
+
+    /// Read `length` bytes with DFU_UPLOAD starting at `first_block`, ending in dfuIDLE.
+    async fn read_back(&self, first_block: u16, length: usize) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::with_capacity(length);
+        self.upload_blocks(first_block, Some(length), &mut data)
+            .await?;
+        Ok(data)
+    }
+
+    /// Program and optionally verify DfuSe `images` of `(address, data)`, then leave DFU mode
+    /// from the first one.
+    pub(crate) async fn dfuse_download(
+        &self,
+        images: &[(u32, &[u8])],
+        options: &DownloadOptions,
+    ) -> Result<(), Error> {
+        let pages = self.dfuse_erase_pages(images)?;
+        let leave = images.first().map(|&(address, _)| address);
+        self.dfuse_download_pages(&pages, images, leave, options)
+            .await
+    }
+
+    /// Erase `pages`, then program and optionally verify `images` of `(address, data)` and
+    /// leave DFU mode from `leave`.
+    pub(crate) async fn dfuse_download_pages(
+        &self,
+        pages: &[ErasePage],
+        images: &[(u32, &[u8])],
+        leave: Option<u32>,
+        options: &DownloadOptions,
+    ) -> Result<(), Error> {
+        self.expect_idle().await?;
+        self.dfuse_erase_and_write(pages, images).await?;
+        if options.verify {
+            self.dfuse_verify(images).await?;
+        }
+        match leave {
+            Some(address) => self.dfuse_leave(address).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Erase the DfuSe pages covered by `images` of `(address, data)`, each page once even if
+    /// several images share it, and write the images, ending in dfuIDLE.
+    pub(crate) async fn dfuse_program(&self, images: &[(u32, &[u8])]) -> Result<(), Error> {
+        let pages = self.dfuse_erase_pages(images)?;
+        self.dfuse_erase_and_write(&pages, images).await
+    }
+
+    /// The DfuSe pages covered by `images` of `(address, data)`, sorted and each listed once.
+    pub(crate) fn dfuse_erase_pages(
+        &self,
+        images: &[(u32, &[u8])],
+    ) -> Result<Vec<ErasePage>, Error> {
+        let mut pages = Vec::new();
+        for &(address, data) in images {
+            pages.extend(self.dfuse_pages(address, data.len())?);
+        }
+        pages.sort_by_key(|page| page.address);
+        pages.dedup();
+        Ok(pages)
+    }
+
+    async fn dfuse_erase_and_write(
+        &self,
+        pages: &[ErasePage],
+        images: &[(u32, &[u8])],
+    ) -> Result<(), Error> {
+        for page in pages {
+            self.dfuse_command(DfuseCommand::Erase(page.address))
+                .await?;
+        }
+        for &(address, data) in images {
+            self.dfuse_write(address, data).await?;
+        }
+        // DFU_UPLOAD and other alternate settings expect dfuIDLE.
+        self.abort().await
+    }
+
+    /// The DfuSe pages covering `length` bytes from `address`.
+    pub(crate) fn dfuse_pages(&self, address: u32, length: usize) -> Result<Vec<ErasePage>, Error> {
+        let DfuProtocol::Dfuse {
+            address: start,
+            memory_layout,
+        } = &self.protocol
+        else {
+            return Ok(Vec::new());
+        };
+        let out_of_range = || Error::AddressOutOfRange { address, length };
+        if address < *start {
+            return Err(out_of_range());
+        }
+        let mut pages = Vec::new();
+        let mut page = *start as u64;
+        let end = address as u64 + length as u64;
+        for &page_size in memory_layout.as_slice() {
+            if page >= end {
+                return Ok(pages);
+            }
+            if page + page_size as u64 > address as u64 {
+                pages.push(ErasePage {
+                    address: u32::try_from(page).map_err(|_| out_of_range())?,
+                    size: page_size,
+                });
+            }
+            page += page_size as u64;
+        }
+        if page < end {
+            return Err(out_of_range());
+        }
+        Ok(pages)
+    }
+
+    /// Write `firmware` to `address` with DfuSe DFU_DNLOAD blocks.
+    pub(crate) async fn dfuse_write(&self, address: u32, firmware: &[u8]) -> Result<(), Error> {
+        self.dfuse_send(address, firmware).await?;
+        self.wait_for_state(State::DfuDnloadIdle).await?;
+        Ok(())
+    }
+
+    /// Send `firmware` to `address` with DfuSe DFU_DNLOAD blocks, waiting until every block but
+    /// the last one was written.
+    pub(crate) async fn dfuse_send(&self, address: u32, firmware: &[u8]) -> Result<(), Error> {
+        self.dfuse_command(DfuseCommand::SetAddress(address))
+            .await?;
+        for (i, chunk) in firmware
+            .chunks(self.descriptor.transfer_size as usize)
+            .enumerate()
+        {
+            if i > 0 {
+                self.wait_for_state(State::DfuDnloadIdle).await?;
+            }
+            // The address of a block is derived from its number, which therefore cannot wrap.
+            let block_num = u16::try_from(i + DFUSE_FIRST_BLOCK as usize)
+                .map_err(|_| dfu_core::Error::MaximumChunksExceeded)?;
+            self.dnload_block(block_num, chunk).await?;
+        }
+        Ok(())
+    }
+
+    /// Read back and compare `images` of `(address, data)`, starting and ending in dfuIDLE.
+    pub(crate) async fn dfuse_verify(&self, images: &[(u32, &[u8])]) -> Result<(), Error> {
+        for &(address, data) in images {
+            let read_back = self.dfuse_read(address, data.len()).await?;
+            compare(data, &read_back, Some(address))?;
+        }
+        Ok(())
+    }
+
+    /// Read `length` bytes of DfuSe memory from `address`, starting and ending in dfuIDLE.
+    pub(crate) async fn dfuse_read(&self, address: u32, length: usize) -> Result<Vec<u8>, Error> {
+        self.dfuse_command(DfuseCommand::SetAddress(address))
+            .await?;
+        // DFU_UPLOAD is only accepted in dfuIDLE.
+        self.abort().await?;
+        self.read_back(DFUSE_FIRST_BLOCK, length).await
+    }
+
+    /// Leave DFU mode, starting the firmware at `address` (UM0391, Section 6.1.4).
+    pub(crate) async fn dfuse_leave(&self, address: u32) -> Result<(), Error> {
+        self.dfuse_command(DfuseCommand::SetAddress(address))
+            .await?;
+        self.dnload_block(DFUSE_FIRST_BLOCK, &[]).await?;
+        // The device leaves DFU mode once it reports its status and may not answer at all.
+        let _ = self.get_status().await;
+        Ok(())
+    }
+}
+
+/// Compare the firmware read back from a device with the downloaded one, written at `address`
+/// on DfuSe devices.
+fn compare(expected: &[u8], actual: &[u8], address: Option<u32>) -> Result<(), Error> {
+    let differs = |i: &usize| actual.get(*i) != Some(&expected[*i]);
+    let Some(offset) = (0..expected.len()).find(differs) else {
+        return Ok(());
+    };
+    let size = (offset..expected.len()).take_while(differs).count();
+    Err(Error::VerifyMismatch {
+        offset,
+        size,
+        address: address.map(|address| address.wrapping_add(offset as u32)),
+    })
+}
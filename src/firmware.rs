@@ -0,0 +1,77 @@
+//! Firmware file formats.
+//!
+//! [`DfuFile`] is a firmware image followed by the DFU suffix (DFU 1.1 Specification,
+//! Appendix B), which names the device the firmware is meant for and protects the file with a
+//! CRC-32. [`DfuseFile`] is ST's DfuSe container in such a file, holding images for several
+//! memories of a device, each made of elements at their own address. [`SparseFirmware`] is
+//! made of segments at their own addresses, as loaded from Intel HEX and Motorola S-record
+//! files. [`ElfFirmware`] loads the segments of an ELF executable.
+
+use std::fmt;
+use thiserror::Error;
+
+mod dfuse;
+mod elf;
+mod ihex;
+mod sparse;
+mod srec;
+mod suffix;
+
+pub use dfuse::{DfuseElement, DfuseFile, DfuseTarget};
+pub use elf::{ElfFirmware, ElfSection};
+pub use sparse::{GapFill, Segment, SparseFirmware};
+pub use suffix::{DfuFile, DfuSuffix};
+
+pub(crate) use sparse::decode_hex;
+
+/// The idVendor, idProduct and bcdDevice of a device, or of the device a firmware is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceIds {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub bcd_device: u16,
+}
+
+impl DeviceIds {
+    /// A DFU suffix field matching every device.
+    pub const WILDCARD: u16 = 0xffff;
+
+    /// Whether firmware for `self` may be flashed to a device with the ids `device`.
+    pub fn matches(&self, device: &DeviceIds) -> bool {
+        let field = |file: u16, device: u16| file == Self::WILDCARD || file == device;
+        field(self.vendor_id, device.vendor_id)
+            && field(self.product_id, device.product_id)
+            && field(self.bcd_device, device.bcd_device)
+    }
+}
+
+impl fmt::Display for DeviceIds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:04x} (bcdDevice {:04x})",
+            self.vendor_id, self.product_id, self.bcd_device
+        )
+    }
+}
+
+/// An invalid firmware file.
+#[derive(Debug, Error)]
+pub enum FileError {
+    #[error("The file has no DFU suffix")]
+    MissingSuffix,
+    #[error("Invalid DFU suffix length {0}")]
+    InvalidSuffixLength(u8),
+    #[error("DFU suffix CRC mismatch, expected {expected:#010x} but the file has {actual:#010x}")]
+    CrcMismatch { expected: u32, actual: u32 },
+    #[error("Invalid DfuSe file: {0}")]
+    InvalidDfuse(&'static str),
+    #[error("Invalid record on line {line}: {reason}")]
+    InvalidRecord { line: usize, reason: &'static str },
+    #[error("Invalid ELF file: {0}")]
+    InvalidElf(&'static str),
+    #[error("Segments overlap at {address:#010x}")]
+    Overlap { address: u32 },
+    #[error("The firmware has a gap from {start:#010x} to {end:#010x}")]
+    Gap { start: u32, end: u32 },
+}
@@ -0,0 +1,226 @@
+//! The DfuSe file format (UM0391, Section 4): images for several alternate settings, each made
+//! of elements written at their own address.
+
+use super::{DfuFile, DfuSuffix, FileError};
+use crate::{DfuCrossUsb, DownloadOptions, Error, UsbTransport};
+
+/// szSignature of the DfuSe prefix.
+const PREFIX_SIGNATURE: &[u8; 5] = b"DfuSe";
+/// bVersion of the DfuSe prefix.
+const PREFIX_VERSION: u8 = 1;
+/// szSignature of a target prefix.
+const TARGET_SIGNATURE: &[u8; 6] = b"Target";
+/// Length of the szTargetName field of a target prefix.
+const TARGET_NAME_LENGTH: usize = 255;
+
+/// A DfuSe file.
+#[derive(Debug, Clone)]
+pub struct DfuseFile<'a> {
+    suffix: DfuSuffix,
+    targets: Vec<DfuseTarget<'a>>,
+}
+
+/// The image of a DfuSe file for one alternate setting.
+#[derive(Debug, Clone)]
+pub struct DfuseTarget<'a> {
+    /// bAlternateSetting, the memory the image is for.
+    pub alt_setting: u8,
+    /// szTargetName, if bTargetNamed is set.
+    pub name: Option<String>,
+    /// The elements of the image.
+    pub elements: Vec<DfuseElement<'a>>,
+}
+
+/// A contiguous piece of a DfuSe image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DfuseElement<'a> {
+    /// dwElementAddress.
+    pub address: u32,
+    /// The data written at `address`.
+    pub data: &'a [u8],
+}
+
+impl<'a> DfuseFile<'a> {
+    /// Parse a DfuSe file and check the CRC of its DFU suffix.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, FileError> {
+        let file = DfuFile::parse(bytes)?;
+        let mut reader = Reader(file.firmware());
+        if reader.take(PREFIX_SIGNATURE.len())? != PREFIX_SIGNATURE {
+            return Err(FileError::InvalidDfuse("missing DfuSe prefix"));
+        }
+        if reader.u8()? != PREFIX_VERSION {
+            return Err(FileError::InvalidDfuse("unsupported DfuSe version"));
+        }
+        // DFUImageSize counts the prefix and the targets, but not the suffix.
+        if reader.u32()? as usize != file.firmware().len() {
+            return Err(FileError::InvalidDfuse(
+                "image size does not match the file",
+            ));
+        }
+
+        let target_count = reader.u8()?;
+        let mut targets = Vec::with_capacity(target_count as usize);
+        for _ in 0..target_count {
+            if reader.take(TARGET_SIGNATURE.len())? != TARGET_SIGNATURE {
+                return Err(FileError::InvalidDfuse("missing target prefix"));
+            }
+            let alt_setting = reader.u8()?;
+            let named = reader.u32()? != 0;
+            let name = reader.take(TARGET_NAME_LENGTH)?;
+            let name = named.then(|| {
+                let end = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+                String::from_utf8_lossy(&name[..end]).into_owned()
+            });
+            let size = reader.u32()? as usize;
+            let element_count = reader.u32()?;
+
+            let mut target = Reader(reader.take(size)?);
+            let mut elements = Vec::new();
+            for _ in 0..element_count {
+                let address = target.u32()?;
+                let size = target.u32()? as usize;
+                elements.push(DfuseElement {
+                    address,
+                    data: target.take(size)?,
+                });
+            }
+            if !target.0.is_empty() {
+                return Err(FileError::InvalidDfuse(
+                    "target size does not match its elements",
+                ));
+            }
+            targets.push(DfuseTarget {
+                alt_setting,
+                name,
+                elements,
+            });
+        }
+        if !reader.0.is_empty() {
+            return Err(FileError::InvalidDfuse(
+                "trailing data after the last target",
+            ));
+        }
+
+        Ok(Self {
+            suffix: *file.suffix(),
+            targets,
+        })
+    }
+
+    /// The suffix of the file.
+    pub fn suffix(&self) -> &DfuSuffix {
+        &self.suffix
+    }
+
+    /// The images of the file, in file order.
+    pub fn targets(&self) -> &[DfuseTarget<'a>] {
+        &self.targets
+    }
+}
+
+impl DfuseTarget<'_> {
+    /// The number of bytes of all elements.
+    pub fn len(&self) -> usize {
+        self.elements.iter().map(|element| element.data.len()).sum()
+    }
+
+    /// Whether the image has no data.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn images(&self) -> Vec<(u32, &[u8])> {
+        self.elements
+            .iter()
+            .map(|element| (element.address, element.data))
+            .collect()
+    }
+}
+
+/// Reads the little-endian fields of a DfuSe file.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], FileError> {
+        if length > self.0.len() {
+            return Err(FileError::InvalidDfuse("truncated file"));
+        }
+        let (bytes, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, FileError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, FileError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+impl<T: UsbTransport + 'static> DfuCrossUsb<T> {
+    /// Download every image of a DfuSe file to its alternate setting and leave DFU mode,
+    /// refusing files for other devices with [`Error::DeviceMismatch`].
+    ///
+    /// The pages of each memory are erased once, even if several elements share them, before
+    /// its elements are written. With [`DownloadOptions::with_verify`] all elements are read
+    /// back after everything was written. Progress counts the bytes of all images. The device
+    /// leaves DFU mode once at the end, from the first element of the file. The address of
+    /// [`DownloadOptions::with_address`] is ignored, elements carry their own.
+    pub async fn download_dfuse_file(
+        &mut self,
+        file: &DfuseFile<'_>,
+        options: &DownloadOptions,
+    ) -> Result<(), Error> {
+        if options.verify && !self.can_verify() {
+            return Err(Error::VerifyNotSupported);
+        }
+        self.check_device(&file.suffix.device).await?;
+        let total = file.targets.iter().map(DfuseTarget::len).sum();
+        self.begin_operation(total);
+        let result = self.download_targets(&file.targets, options).await;
+        self.progress_tracker().end();
+        result
+    }
+
+    async fn download_targets(
+        &mut self,
+        targets: &[DfuseTarget<'_>],
+        options: &DownloadOptions,
+    ) -> Result<(), Error> {
+        let Some((first_target, first_element)) = targets
+            .iter()
+            .find_map(|target| Some((target, target.elements.first()?)))
+        else {
+            return Ok(());
+        };
+
+        for target in targets {
+            self.set_dfuse_alt_setting(target.alt_setting).await?;
+            self.expect_idle().await?;
+            self.dfuse_program(&target.images()).await?;
+        }
+        if options.verify {
+            for target in targets {
+                self.set_dfuse_alt_setting(target.alt_setting).await?;
+                self.dfuse_verify(&target.images()).await?;
+            }
+        }
+
+        self.set_dfuse_alt_setting(first_target.alt_setting).await?;
+        self.dfuse_leave(first_element.address).await
+    }
+
+    /// Select the alternate setting of a DfuSe target.
+    pub(crate) async fn set_dfuse_alt_setting(&mut self, alt_setting: u8) -> Result<(), Error> {
+        if alt_setting != self.alt_setting {
+            self.set_alt_setting(alt_setting).await?;
+        }
+        if !self.is_dfuse() {
+            return Err(Error::DfuseNotSupported);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,244 @@
+//! ELF executables, loaded from their PT_LOAD program headers (System V ABI, Chapter 5).
+
+use super::{FileError, Segment, SparseFirmware};
+use crate::{DfuCrossUsb, DownloadOptions, Error, UsbTransport, memory};
+
+const MAGIC: &[u8; 4] = b"\x7fELF";
+const CLASS_32: u8 = 1;
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const DATA_BIG_ENDIAN: u8 = 2;
+/// p_type of a loadable segment.
+const PT_LOAD: u32 = 1;
+/// sh_type of a section without data in the file, like `.bss`.
+const SHT_NOBITS: u32 = 8;
+/// sh_flags bit of a section occupying memory at run time.
+const SHF_ALLOC: u64 = 0x2;
+
+/// Firmware loaded from an ELF executable.
+///
+/// The data of the PT_LOAD segments is placed at their physical address (LMA), where it is
+/// stored in flash, rather than at their virtual address, where it may be copied to at run
+/// time like `.data`.
+#[derive(Debug, Clone)]
+pub struct ElfFirmware {
+    firmware: SparseFirmware,
+    sections: Vec<ElfSection>,
+}
+
+/// An allocated section with data of an ELF executable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElfSection {
+    /// The name of the section, e.g. `.text`.
+    pub name: String,
+    /// The physical address (LMA) of the section.
+    pub address: u32,
+    /// The number of bytes of the section.
+    pub size: usize,
+}
+
+impl ElfFirmware {
+    /// Parse a 32 or 64 bit ELF executable of either endianness.
+    pub fn parse(bytes: &[u8]) -> Result<Self, FileError> {
+        let invalid = FileError::InvalidElf;
+        if bytes.get(..4) != Some(MAGIC) {
+            return Err(invalid("missing ELF magic"));
+        }
+        let class = *bytes.get(4).ok_or(invalid("truncated file"))?;
+        let big_endian = match bytes.get(5) {
+            Some(&DATA_LITTLE_ENDIAN) => false,
+            Some(&DATA_BIG_ENDIAN) => true,
+            _ => return Err(invalid("unsupported data encoding")),
+        };
+        let elf = Reader { bytes, big_endian };
+
+        // e_phoff, e_shoff, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx.
+        let (program_headers, section_headers, numbers) = match class {
+            CLASS_32 => (elf.u32(28)? as u64, elf.u32(32)? as u64, 42),
+            CLASS_64 => (elf.u64(32)?, elf.u64(40)?, 54),
+            _ => return Err(invalid("unsupported class")),
+        };
+        let program_header_size = elf.u16(numbers)? as u64;
+        let program_header_count = elf.u16(numbers + 2)? as u64;
+        let section_header_size = elf.u16(numbers + 4)? as u64;
+        let section_header_count = elf.u16(numbers + 6)? as u64;
+        let names_index = elf.u16(numbers + 8)? as u64;
+
+        let mut loads = Vec::new();
+        for i in 0..program_header_count {
+            let header = elf.entry(program_headers, i, program_header_size)?;
+            let (offset, physical_address, file_size) = match class {
+                CLASS_32 => (
+                    header.u32(4)? as u64,
+                    header.u32(12)? as u64,
+                    header.u32(16)? as u64,
+                ),
+                _ => (header.u64(8)?, header.u64(24)?, header.u64(32)?),
+            };
+            if header.u32(0)? != PT_LOAD || file_size == 0 {
+                continue;
+            }
+            let address =
+                u32::try_from(physical_address).map_err(|_| invalid("address out of range"))?;
+            loads.push((offset, address, elf.bytes(offset, file_size)?));
+        }
+
+        let mut headers = Vec::new();
+        for i in 0..section_header_count {
+            let header = elf.entry(section_headers, i, section_header_size)?;
+            let (flags, offset, size) = match class {
+                CLASS_32 => (
+                    header.u32(8)? as u64,
+                    header.u32(16)? as u64,
+                    header.u32(20)? as u64,
+                ),
+                _ => (header.u64(8)?, header.u64(24)?, header.u64(32)?),
+            };
+            headers.push((header.u32(0)?, header.u32(4)?, flags, offset, size));
+        }
+        let names = match headers.get(names_index as usize) {
+            Some(&(_, _, _, offset, size)) => elf.bytes(offset, size)?,
+            None => &[],
+        };
+
+        // A section is stored in flash at the LMA of the segment holding its file data.
+        let mut sections = Vec::new();
+        for &(name, kind, flags, offset, size) in &headers {
+            if kind == SHT_NOBITS || flags & SHF_ALLOC == 0 || size == 0 {
+                continue;
+            }
+            let Some(&(load_offset, load_address, _)) =
+                loads.iter().find(|&&(load_offset, _, data)| {
+                    (load_offset..load_offset + data.len() as u64).contains(&offset)
+                })
+            else {
+                continue;
+            };
+            let name = names.get(name as usize..).unwrap_or_default();
+            let end = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+            sections.push(ElfSection {
+                name: String::from_utf8_lossy(&name[..end]).into_owned(),
+                address: load_address.wrapping_add((offset - load_offset) as u32),
+                size: size as usize,
+            });
+        }
+
+        let segments = loads
+            .into_iter()
+            .map(|(_, address, data)| Segment {
+                address,
+                data: data.to_vec(),
+            })
+            .collect();
+        Ok(Self {
+            firmware: SparseFirmware::from_segments(segments)?,
+            sections,
+        })
+    }
+
+    /// The data of the PT_LOAD segments at their physical address.
+    pub fn firmware(&self) -> &SparseFirmware {
+        &self.firmware
+    }
+
+    /// The allocated sections with data, at their physical address.
+    pub fn sections(&self) -> &[ElfSection] {
+        &self.sections
+    }
+
+    /// The name of the section holding `address`, or of the segment if no section does.
+    fn name_at(&self, address: u32) -> String {
+        let contains = |start: u32, size: usize| {
+            (start as u64..start as u64 + size as u64).contains(&(address as u64))
+        };
+        if let Some(section) = self
+            .sections
+            .iter()
+            .find(|section| contains(section.address, section.size))
+        {
+            return section.name.clone();
+        }
+        let segment = self
+            .firmware
+            .segments()
+            .iter()
+            .find(|segment| contains(segment.address, segment.data.len()))
+            .map_or(address, |segment| segment.address);
+        format!("segment at {segment:#010x}")
+    }
+}
+
+/// Reads the fields of an ELF file.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&self, offset: u64, length: u64) -> Result<&'a [u8], FileError> {
+        offset
+            .checked_add(length)
+            .and_then(|end| {
+                let start = usize::try_from(offset).ok()?;
+                self.bytes.get(start..usize::try_from(end).ok()?)
+            })
+            .ok_or(FileError::InvalidElf("truncated file"))
+    }
+
+    /// Entry `index` of a table of `size` byte entries at `offset`.
+    fn entry(&self, offset: u64, index: u64, size: u64) -> Result<Reader<'a>, FileError> {
+        Ok(Reader {
+            bytes: self.bytes(offset.saturating_add(index * size), size)?,
+            big_endian: self.big_endian,
+        })
+    }
+
+    fn field<const N: usize>(&self, offset: u64) -> Result<[u8; N], FileError> {
+        let mut field = [0; N];
+        field.copy_from_slice(self.bytes(offset, N as u64)?);
+        if self.big_endian {
+            field.reverse();
+        }
+        Ok(field)
+    }
+
+    fn u16(&self, offset: u64) -> Result<u16, FileError> {
+        self.field(offset).map(u16::from_le_bytes)
+    }
+
+    fn u32(&self, offset: u64) -> Result<u32, FileError> {
+        self.field(offset).map(u32::from_le_bytes)
+    }
+
+    fn u64(&self, offset: u64) -> Result<u64, FileError> {
+        self.field(offset).map(u64::from_le_bytes)
+    }
+}
+
+impl<T: UsbTransport + 'static> DfuCrossUsb<T> {
+    /// Download an ELF executable like [`download_sparse`](Self::download_sparse).
+    ///
+    /// On DfuSe devices every segment must lie in writable pages of the memory of the
+    /// alternate setting, otherwise the download is refused with [`Error::NotWritable`] naming
+    /// the offending section before anything is written.
+    pub async fn download_elf(
+        &self,
+        firmware: &ElfFirmware,
+        options: &DownloadOptions,
+    ) -> Result<(), Error> {
+        let pages = memory::pages(&self.interface_string, &self.protocol);
+        if self.is_dfuse() {
+            for segment in firmware.firmware.segments() {
+                if let Some(address) =
+                    memory::first_unwritable(&pages, segment.address, segment.data.len())
+                {
+                    return Err(Error::NotWritable {
+                        section: firmware.name_at(address),
+                        address,
+                    });
+                }
+            }
+        }
+        self.download_sparse(&firmware.firmware, options).await
+    }
+}
@@ -0,0 +1,67 @@
+//! Intel HEX files.
+
+use super::sparse::decode_hex;
+use super::{FileError, Segment, SparseFirmware};
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+impl SparseFirmware {
+    /// Parse an Intel HEX file with 16 or 32 bit addresses. Start address records are ignored.
+    pub fn parse_intel_hex(text: &str) -> Result<Self, FileError> {
+        let mut segments = Vec::new();
+        let mut base = 0u32;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = |reason| FileError::InvalidRecord {
+                line: i + 1,
+                reason,
+            };
+            let record = line
+                .strip_prefix(':')
+                .ok_or_else(|| invalid("missing start code"))?;
+            let bytes = decode_hex(record).ok_or_else(|| invalid("invalid hex digits"))?;
+            if bytes.len() < 5 || bytes.len() != 5 + bytes[0] as usize {
+                return Err(invalid("invalid length"));
+            }
+            // The checksum makes the bytes of the record add up to zero.
+            if bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0 {
+                return Err(invalid("checksum mismatch"));
+            }
+
+            let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+            let data = &bytes[4..bytes.len() - 1];
+            match (bytes[3], data) {
+                (DATA, _) => segments.push(Segment {
+                    address: base
+                        .checked_add(offset)
+                        .ok_or_else(|| invalid("address out of range"))?,
+                    data: data.to_vec(),
+                }),
+                (END_OF_FILE, _) => return SparseFirmware::from_segments(segments),
+                (EXTENDED_SEGMENT_ADDRESS, &[high, low]) => {
+                    base = (u16::from_be_bytes([high, low]) as u32) << 4;
+                }
+                (EXTENDED_LINEAR_ADDRESS, &[high, low]) => {
+                    base = (u16::from_be_bytes([high, low]) as u32) << 16;
+                }
+                (START_SEGMENT_ADDRESS | START_LINEAR_ADDRESS, _) => {}
+                (EXTENDED_SEGMENT_ADDRESS | EXTENDED_LINEAR_ADDRESS, _) => {
+                    return Err(invalid("invalid length"));
+                }
+                _ => return Err(invalid("unsupported record type")),
+            }
+        }
+        Err(FileError::InvalidRecord {
+            line: text.lines().count(),
+            reason: "missing end of file record",
+        })
+    }
+}
@@ -0,0 +1,154 @@
+//! Firmware made of segments at their own addresses, as loaded from Intel HEX or S-record
+//! files.
+
+use super::FileError;
+use crate::{DfuCrossUsb, DownloadOptions, Error, UsbTransport};
+use dfu_core::DfuProtocol;
+
+/// A contiguous piece of firmware.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    /// The address of the first byte.
+    pub address: u32,
+    /// The bytes of the segment.
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    /// The address following the last byte.
+    pub fn end(&self) -> u64 {
+        self.address as u64 + self.data.len() as u64
+    }
+}
+
+/// How [`SparseFirmware::flatten`] handles the gaps between segments.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GapFill {
+    /// Refuse firmware with gaps with [`FileError::Gap`].
+    #[default]
+    Refuse,
+    /// Fill gaps with a byte, e.g. `0xff` for erased flash.
+    Fill(u8),
+}
+
+/// Firmware made of non-overlapping segments, sorted by address.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SparseFirmware {
+    segments: Vec<Segment>,
+}
+
+impl SparseFirmware {
+    /// Sort segments by address and merge adjacent ones, refusing segments that overlap with
+    /// [`FileError::Overlap`].
+    pub fn from_segments(mut segments: Vec<Segment>) -> Result<Self, FileError> {
+        segments.retain(|segment| !segment.data.is_empty());
+        segments.sort_by_key(|segment| segment.address);
+        let mut merged: Vec<Segment> = Vec::with_capacity(segments.len());
+        for segment in segments {
+            match merged.last_mut() {
+                Some(last) if last.end() > segment.address as u64 => {
+                    return Err(FileError::Overlap {
+                        address: segment.address,
+                    });
+                }
+                Some(last) if last.end() == segment.address as u64 => {
+                    last.data.extend_from_slice(&segment.data);
+                }
+                _ => merged.push(segment),
+            }
+        }
+        Ok(Self { segments: merged })
+    }
+
+    /// The segments, sorted by address.
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// The number of bytes of all segments.
+    pub fn len(&self) -> usize {
+        self.segments.iter().map(|segment| segment.data.len()).sum()
+    }
+
+    /// Whether the firmware has no data.
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Join the segments into a single one starting at the lowest address, handling the gaps
+    /// between them according to `fill`.
+    pub fn flatten(&self, fill: GapFill) -> Result<Segment, FileError> {
+        let Some(first) = self.segments.first() else {
+            return Ok(Segment {
+                address: 0,
+                data: Vec::new(),
+            });
+        };
+        let mut data = Vec::new();
+        for segment in &self.segments {
+            let end = first.address as u64 + data.len() as u64;
+            if end < segment.address as u64 {
+                let GapFill::Fill(byte) = fill else {
+                    return Err(FileError::Gap {
+                        start: end as u32,
+                        end: segment.address,
+                    });
+                };
+                data.resize((segment.address - first.address) as usize, byte);
+            }
+            data.extend_from_slice(&segment.data);
+        }
+        Ok(Segment {
+            address: first.address,
+            data,
+        })
+    }
+
+    pub(crate) fn images(&self) -> Vec<(u32, &[u8])> {
+        self.segments
+            .iter()
+            .map(|segment| (segment.address, segment.data.as_slice()))
+            .collect()
+    }
+}
+
+impl<T: UsbTransport + 'static> DfuCrossUsb<T> {
+    /// Download sparse firmware and manifest it.
+    ///
+    /// DfuSe devices get each segment written at its address, erasing the pages the segments
+    /// cover once. DFU 1.1 devices have no addresses: the segments are joined according to
+    /// [`DownloadOptions::with_gap_fill`] and downloaded like [`download`](Self::download),
+    /// the lowest address becoming the start of the firmware.
+    pub async fn download_sparse(
+        &self,
+        firmware: &SparseFirmware,
+        options: &DownloadOptions,
+    ) -> Result<(), Error> {
+        if let DfuProtocol::Dfu = self.protocol {
+            let image = firmware.flatten(options.gap_fill)?;
+            return self.download(&image.data, options).await;
+        }
+        if options.verify && !self.can_verify() {
+            return Err(Error::VerifyNotSupported);
+        }
+        if firmware.is_empty() {
+            return Ok(());
+        }
+
+        self.begin_operation(firmware.len());
+        let result = self.dfuse_download(&firmware.images(), options).await;
+        self.progress_tracker().end();
+        result
+    }
+}
+
+/// Decode pairs of hex digits.
+pub(crate) fn decode_hex(digits: &str) -> Option<Vec<u8>> {
+    if !digits.len().is_multiple_of(2) || !digits.bytes().all(|digit| digit.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).ok())
+        .collect()
+}
@@ -0,0 +1,57 @@
+//! Motorola S-record files.
+
+use super::sparse::decode_hex;
+use super::{FileError, Segment, SparseFirmware};
+
+impl SparseFirmware {
+    /// Parse a Motorola S-record file with 16, 24 or 32 bit addresses. Header, count and start
+    /// address records are ignored.
+    pub fn parse_srec(text: &str) -> Result<Self, FileError> {
+        let mut segments = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = |reason| FileError::InvalidRecord {
+                line: i + 1,
+                reason,
+            };
+            let record = line
+                .strip_prefix('S')
+                .ok_or_else(|| invalid("missing start code"))?;
+            let (kind, record) = record.split_at_checked(1).unwrap_or(("", record));
+            let address_length = match kind {
+                "0" | "1" | "5" | "9" => 2,
+                "2" | "6" | "8" => 3,
+                "3" | "7" => 4,
+                _ => return Err(invalid("unsupported record type")),
+            };
+            let bytes = decode_hex(record).ok_or_else(|| invalid("invalid hex digits"))?;
+            if bytes.len() < 2 + address_length || bytes.len() != 1 + bytes[0] as usize {
+                return Err(invalid("invalid length"));
+            }
+            // The checksum is the one's complement of the sum of the other bytes.
+            if bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0xff {
+                return Err(invalid("checksum mismatch"));
+            }
+
+            let address = bytes[1..1 + address_length]
+                .iter()
+                .fold(0u32, |address, &byte| address << 8 | byte as u32);
+            let data = &bytes[1 + address_length..bytes.len() - 1];
+            match kind {
+                "1" | "2" | "3" => segments.push(Segment {
+                    address,
+                    data: data.to_vec(),
+                }),
+                "7" | "8" | "9" => return SparseFirmware::from_segments(segments),
+                _ => {}
+            }
+        }
+        Err(FileError::InvalidRecord {
+            line: text.lines().count(),
+            reason: "missing termination record",
+        })
+    }
+}
@@ -0,0 +1,113 @@
+//! The DFU suffix (DFU 1.1 Specification, Appendix B).
+
+use super::{DeviceIds, FileError};
+use crate::crc::crc32;
+use crate::{DfuCrossUsb, DownloadOptions, Error, UsbTransport};
+
+/// Length of the DFU 1.1 suffix, longer suffixes carry vendor specific fields in front of it.
+const SUFFIX_LENGTH: usize = 16;
+/// ucDfuSignature, "DFU" stored backwards.
+const SIGNATURE: &[u8; 3] = b"UFD";
+
+/// The fields of a DFU suffix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DfuSuffix {
+    /// The device the firmware is for, [`DeviceIds::WILDCARD`] fields match any device.
+    pub device: DeviceIds,
+    /// bcdDFU, `0x0100` for DFU 1.0/1.1 and `0x011a` for DfuSe files.
+    pub dfu_version: u16,
+    /// bLength, the number of bytes of the suffix.
+    pub length: u8,
+    /// dwCRC.
+    pub crc: u32,
+}
+
+/// A firmware file with a DFU suffix.
+#[derive(Debug, Clone, Copy)]
+pub struct DfuFile<'a> {
+    firmware: &'a [u8],
+    suffix: DfuSuffix,
+}
+
+impl<'a> DfuFile<'a> {
+    /// Parse a file ending with a DFU suffix and check its CRC.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, FileError> {
+        let start = bytes
+            .len()
+            .checked_sub(SUFFIX_LENGTH)
+            .ok_or(FileError::MissingSuffix)?;
+        let suffix = &bytes[start..];
+        if &suffix[8..11] != SIGNATURE {
+            return Err(FileError::MissingSuffix);
+        }
+        let length = suffix[11];
+        if (length as usize) < SUFFIX_LENGTH || length as usize > bytes.len() {
+            return Err(FileError::InvalidSuffixLength(length));
+        }
+
+        // dwCRC covers the whole file but itself, without the final inversion of CRC-32.
+        let crc = u32::from_le_bytes([suffix[12], suffix[13], suffix[14], suffix[15]]);
+        let expected = !crc32(&bytes[..bytes.len() - 4]);
+        if crc != expected {
+            return Err(FileError::CrcMismatch {
+                expected,
+                actual: crc,
+            });
+        }
+
+        let field = |i: usize| u16::from_le_bytes([suffix[i], suffix[i + 1]]);
+        Ok(Self {
+            firmware: &bytes[..bytes.len() - length as usize],
+            suffix: DfuSuffix {
+                device: DeviceIds {
+                    bcd_device: field(0),
+                    product_id: field(2),
+                    vendor_id: field(4),
+                },
+                dfu_version: field(6),
+                length,
+                crc,
+            },
+        })
+    }
+
+    /// The firmware without the suffix.
+    pub fn firmware(&self) -> &'a [u8] {
+        self.firmware
+    }
+
+    /// The suffix of the file.
+    pub fn suffix(&self) -> &DfuSuffix {
+        &self.suffix
+    }
+}
+
+impl<T: UsbTransport + 'static> DfuCrossUsb<T> {
+    /// Check that a DFU file is meant for this device.
+    pub async fn check_file(&self, file: &DfuFile<'_>) -> Result<(), Error> {
+        self.check_device(&file.suffix.device).await
+    }
+
+    /// Check that firmware for the device `file` may be flashed to this device.
+    pub(crate) async fn check_device(&self, file: &DeviceIds) -> Result<(), Error> {
+        let device = self.device_ids().await?;
+        if !file.matches(&device) {
+            return Err(Error::DeviceMismatch {
+                file: *file,
+                device,
+            });
+        }
+        Ok(())
+    }
+
+    /// Download the firmware of a DFU file, refusing files for other devices with
+    /// [`Error::DeviceMismatch`].
+    pub async fn download_file(
+        &self,
+        file: &DfuFile<'_>,
+        options: &DownloadOptions,
+    ) -> Result<(), Error> {
+        self.check_file(file).await?;
+        self.download(file.firmware, options).await
+    }
+}
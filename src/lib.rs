@@ -1,33 +1,146 @@
-use cross_usb::usb::{
-    ControlIn, ControlOut, ControlType, Recipient, UsbDevice, UsbDeviceInfo, UsbInterface,
-};
-use dfu_core::DfuProtocol;
-use futures::channel::oneshot;
+use dfu_core::{DfuProtocol, State};
+use futures::channel::mpsc;
+#[cfg(not(target_family = "wasm"))]
 use futures::executor::block_on;
-use std::rc::Rc;
+use progress::ProgressTracker;
+use runtime::Shared;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
 use thiserror::Error;
-use usb::standard_request;
-use wasm_bindgen_futures::spawn_local;
-
+use transport::MaybeSend;
+use usb::{descriptor_type, request_type, standard_request};
+
+mod backup;
+mod cancel;
+mod crc;
+mod descriptor;
+mod detach;
+mod dfuse;
+pub mod discover;
+mod download;
+pub mod firmware;
+mod memory;
+mod option_bytes;
+mod plan;
+pub mod progress;
+mod recovery;
+mod request;
+mod runtime;
+#[cfg(feature = "sim")]
+pub mod sim;
+mod status;
+pub mod transport;
+mod upload;
+
+pub use backup::BackupReport;
+pub use cancel::CancelHandle;
 pub use cross_usb;
 pub use dfu_core;
+pub use dfuse::{Dfuse, DfuseCommand};
+pub use discover::{DfuInterface, DfuMode, DiscoveredDevice, discover};
+pub use download::DownloadOptions;
+pub use firmware::{DeviceIds, DfuFile, DfuseFile, ElfFirmware, GapFill, SparseFirmware};
+pub use option_bytes::{
+    OptionBytes, OptionBytesError, OptionBytesWrite, ReadProtection, Stm32Family, UserOptions,
+};
+pub use plan::{ErasePage, FlashPlan, PageHash};
+pub use progress::{Phase, Progress};
+pub use recovery::{Recovery, RecoveryAction};
+pub use request::DfuRequest;
+pub use status::DeviceError;
+pub use transport::{CrossUsbTransport, UsbTransport};
+pub use upload::UploadOptions;
 
 // DFU-specific descriptor constants (DFU 1.1 Specification, Section 4.2.4)
 // Reference: https://www.usb.org/sites/default/files/DFU_1.1.pdf
 const DFU_FUNCTIONAL_DESCRIPTOR_TYPE: u8 = 0x21;
 const DFU_FUNCTIONAL_DESCRIPTOR_INDEX: u8 = 0x00;
 
-pub type DfuSync = dfu_core::sync::DfuSync<DfuCrossUsb, Error>;
-pub type DfuAsync = dfu_core::asynchronous::DfuASync<DfuCrossUsb, Error>;
+/// bcdDFUVersion of ST's DfuSe extension of DFU 1.1.
+const DFUSE_VERSION: (u8, u8) = (0x01, 0x1a);
+
+// DFU class requests (DFU 1.1 Specification, Section 3)
+const DFU_DETACH: u8 = 0;
+const DFU_DNLOAD: u8 = 1;
+const DFU_UPLOAD: u8 = 2;
+const DFU_GETSTATUS: u8 = 3;
+const DFU_CLRSTATUS: u8 = 4;
+const DFU_GETSTATE: u8 = 5;
+const DFU_ABORT: u8 = 6;
+
+/// bmRequestType of host-to-device DFU class requests.
+const DFU_REQUEST_OUT: u8 = request_type::direction::OUT
+    | request_type::request_type::CLASS
+    | request_type::recipient::INTERFACE;
+/// bmRequestType of device-to-host DFU class requests.
+const DFU_REQUEST_IN: u8 = request_type::direction::IN
+    | request_type::request_type::CLASS
+    | request_type::recipient::INTERFACE;
+
+/// Blocking DFU operations.
+///
+/// Not available on WASM: blocking the browser event loop, or the event loop of a worker, stops
+/// the USB transfers it would wait for, so it could only hang.
+#[cfg(not(target_family = "wasm"))]
+pub type DfuSync<T = CrossUsbTransport> = dfu_core::sync::DfuSync<DfuCrossUsb<T>, Error>;
+pub type DfuAsync<T = CrossUsbTransport> = dfu_core::asynchronous::DfuASync<DfuCrossUsb<T>, Error>;
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Device not found")]
     DeviceNotFound,
+    #[error("More than one device matches")]
+    MultipleDevicesFound,
     #[error("Functional Desciptor not found")]
     FunctionalDescriptorNotFound,
+    #[error("Configuration Descriptor not found")]
+    ConfigurationDescriptorNotFound,
     #[error("Alternative setting not found")]
     AltSettingNotFound,
+    #[error("The functional descriptor has a wTransferSize of 0")]
+    ZeroTransferSize,
+    #[error("Verification failed, {size} bytes differ at offset {offset:#x}")]
+    VerifyMismatch {
+        offset: usize,
+        size: usize,
+        /// The address of the first difference on DfuSe devices.
+        address: Option<u32>,
+    },
+    #[error("{length} bytes at {address:#010x} are outside the memory of the alternate setting")]
+    AddressOutOfRange { address: u32, length: usize },
+    #[error(
+        "Section {section} at {address:#010x} is not in writable memory of the alternate setting"
+    )]
+    NotWritable { section: String, address: u32 },
+    #[error("The flash plan does not match the memory layout at {address:#010x}")]
+    PlanMismatch { address: u32 },
+    #[error("The alternate setting is not a DfuSe memory")]
+    DfuseNotSupported,
+    #[error("The device does not support read-back verification")]
+    VerifyNotSupported,
+    #[error("The device does not support DFU_UPLOAD")]
+    UploadNotSupported,
+    #[error("The USB transfer was abandoned before it completed")]
+    TransferAbandoned,
+    #[error("setTimeout is not available")]
+    TimerUnavailable,
+    #[error("The transfer was cancelled with a CancelHandle")]
+    Aborted,
+    #[error("{request} timed out")]
+    Timeout { request: DfuRequest },
+    #[error(transparent)]
+    Device(#[from] DeviceError),
+    #[error("DfuSe {command} failed: {source}")]
+    DfuseCommand {
+        command: DfuseCommand,
+        source: DeviceError,
+    },
+    #[error("The firmware is for {file}, not for {device}")]
+    DeviceMismatch { file: DeviceIds, device: DeviceIds },
+    #[error(transparent)]
+    OptionBytes(#[from] OptionBytesError),
+    #[error(transparent)]
+    File(#[from] firmware::FileError),
     #[error(transparent)]
     FunctionalDescriptor(#[from] dfu_core::functional_descriptor::Error),
     #[error(transparent)]
@@ -38,73 +151,349 @@ pub enum Error {
     Io(#[from] std::io::Error),
 }
 
-pub struct DfuCrossUsb {
-    device: Rc<cross_usb::Device>,
-    interface: Rc<cross_usb::Interface>,
+pub struct DfuCrossUsb<T = CrossUsbTransport> {
+    transport: Shared<T>,
     interface_number: u8,
+    alt_setting: u8,
+    interface_string: String,
+    mode: DfuMode,
+    descriptor: dfu_core::functional_descriptor::FunctionalDescriptor,
+    protocol: dfu_core::DfuProtocol<dfu_core::memory_layout::MemoryLayout>,
+    progress: Shared<Mutex<ProgressTracker>>,
+    cancel: CancelHandle,
+    timeouts: Timeouts,
+    /// The last DFU_DNLOAD or DFU_UPLOAD block whose status was not requested yet.
+    last_block: Shared<Mutex<Option<u16>>>,
+}
+
+/// How long control transfers may take.
+#[derive(Debug, Clone, Copy)]
+struct Timeouts {
+    transfer: Duration,
+    /// DFU_GETSTATUS requests and polling while the device manifests the firmware.
+    manifestation: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            transfer: Duration::from_secs(5),
+            manifestation: Duration::from_secs(30),
+        }
+    }
+}
+
+/// The descriptors of the selected alternate setting.
+struct AltSetting {
+    interface_string: String,
+    mode: DfuMode,
     descriptor: dfu_core::functional_descriptor::FunctionalDescriptor,
     protocol: dfu_core::DfuProtocol<dfu_core::memory_layout::MemoryLayout>,
 }
 
-impl DfuCrossUsb {
+impl DfuCrossUsb<CrossUsbTransport> {
     /// Open a USB device for DFU
     pub async fn open(
         device_info: cross_usb::DeviceInfo,
         interface_number: u8,
         alternative_setting: u8,
     ) -> Result<Self, Error> {
-        let device = device_info.open().await?;
-        let interface = device.open_interface(interface_number).await?;
+        let transport = CrossUsbTransport::open(device_info, interface_number).await?;
+        Self::from_transport(transport, interface_number, alternative_setting).await
+    }
+}
 
+impl<T: UsbTransport + 'static> DfuCrossUsb<T> {
+    /// Use an already opened transport for DFU
+    pub async fn from_transport(
+        transport: T,
+        interface_number: u8,
+        alternative_setting: u8,
+    ) -> Result<Self, Error> {
+        let timeouts = Timeouts::default();
+        let alt = Self::select_alt_setting(
+            &transport,
+            interface_number,
+            alternative_setting,
+            timeouts.transfer,
+        )
+        .await?;
+        let dfuse = matches!(alt.protocol, DfuProtocol::Dfuse { .. });
+        Ok(Self {
+            transport: Shared::new(transport),
+            interface_number,
+            alt_setting: alternative_setting,
+            interface_string: alt.interface_string,
+            mode: alt.mode,
+            descriptor: alt.descriptor,
+            protocol: alt.protocol,
+            progress: Shared::new(Mutex::new(ProgressTracker::new(dfuse))),
+            cancel: CancelHandle::default(),
+            timeouts,
+            last_block: Shared::default(),
+        })
+    }
+
+    /// Switch to another alternate setting of the DFU interface, e.g. another memory of a DfuSe
+    /// device.
+    pub async fn set_alt_setting(&mut self, alternative_setting: u8) -> Result<(), Error> {
+        let alt = Self::select_alt_setting(
+            &self.transport,
+            self.interface_number,
+            alternative_setting,
+            self.timeouts.transfer,
+        )
+        .await?;
+        self.progress_tracker()
+            .set_dfuse(matches!(alt.protocol, DfuProtocol::Dfuse { .. }));
+        self.alt_setting = alternative_setting;
+        self.interface_string = alt.interface_string;
+        self.mode = alt.mode;
+        self.descriptor = alt.descriptor;
+        self.protocol = alt.protocol;
+        Ok(())
+    }
+
+    /// Select an alternate setting and read its descriptors, each request within `timeout`.
+    async fn select_alt_setting(
+        transport: &T,
+        interface_number: u8,
+        alternative_setting: u8,
+        timeout: Duration,
+    ) -> Result<AltSetting, Error> {
         // Set alternative setting via SET_INTERFACE standard interface request.
         // https://www.beyondlogic.org/usbnutshell/usb6.shtml#StandardDeviceRequests
-        interface
-            .control_out(ControlOut {
-                control_type: ControlType::Standard,
-                recipient: Recipient::Interface,
-                request: standard_request::SET_INTERFACE,
-                value: alternative_setting as u16,
-                index: interface_number as u16,
-                data: &[],
-            })
-            .await?;
+        let set_interface = transport.control_out(
+            request_type::direction::OUT
+                | request_type::request_type::STANDARD
+                | request_type::recipient::INTERFACE,
+            standard_request::SET_INTERFACE,
+            alternative_setting as u16,
+            interface_number as u16,
+            &[],
+        );
+        bounded(timeout, DfuRequest::SetInterface, set_interface).await?;
+
+        let (descriptor, interface) = Self::read_functional_descriptor(
+            transport,
+            interface_number,
+            alternative_setting,
+            timeout,
+        )
+        .await?;
+
+        // The iInterface string of a DfuSe alternate setting describes its memory layout, e.g.
+        // "@Internal Flash /0x08000000/04*016Kg,01*064Kg,07*128Kg".
+        let interface_string = match interface.map(|i| i.interface_string) {
+            Some(index) if index != 0 => match bounded(
+                timeout,
+                DfuRequest::GetDescriptor,
+                transport.string_descriptor(index),
+            )
+            .await
+            {
+                Ok(interface_string) => interface_string,
+                Err(e) if descriptor.dfu_version == DFUSE_VERSION => return Err(e),
+                Err(_) => String::new(),
+            },
+            _ => String::new(),
+        };
+
+        let mode = match interface.map(|i| i.protocol) {
+            Some(descriptor::RUNTIME_PROTOCOL) => DfuMode::Runtime,
+            _ => DfuMode::Dfu,
+        };
+
+        // Blocks are wTransferSize bytes long, no download or upload could make progress.
+        if mode == DfuMode::Dfu && descriptor.transfer_size == 0 {
+            return Err(Error::ZeroTransferSize);
+        }
+
+        // A run-time interface has no memory layout, its DFU mode counterpart does.
+        let protocol = match mode {
+            DfuMode::Runtime => DfuProtocol::Dfu,
+            DfuMode::Dfu => DfuProtocol::new(&interface_string, descriptor.dfu_version)?,
+        };
+
+        Ok(AltSetting {
+            interface_string,
+            mode,
+            descriptor,
+            protocol,
+        })
+    }
+
+    /// Find the DFU functional descriptor and the interface descriptor of an interface alternate
+    /// setting.
+    ///
+    /// The functional descriptor is part of the configuration descriptor. Devices whose
+    /// configuration descriptor cannot be read or lacks a functional descriptor are asked for it
+    /// directly with a GET_DESCRIPTOR request.
+    async fn read_functional_descriptor(
+        transport: &T,
+        interface_number: u8,
+        alternative_setting: u8,
+        timeout: Duration,
+    ) -> Result<
+        (
+            dfu_core::functional_descriptor::FunctionalDescriptor,
+            Option<descriptor::InterfaceDescriptor>,
+        ),
+        Error,
+    > {
+        let mut interface_descriptor = None;
+        let configuration = bounded(
+            timeout,
+            DfuRequest::GetDescriptor,
+            transport.configuration_descriptor(0),
+        )
+        .await;
+        if let Err(e @ Error::Timeout { .. }) = configuration {
+            return Err(e);
+        }
+        if let Ok(configuration) = configuration {
+            let interface =
+                descriptor::find_interface(&configuration, interface_number, alternative_setting)
+                    .ok_or(Error::AltSettingNotFound)?;
+            interface_descriptor = Some(interface.descriptor);
+            if let Some(bytes) = interface.functional_descriptor {
+                let descriptor =
+                    dfu_core::functional_descriptor::FunctionalDescriptor::from_bytes(bytes)
+                        .ok_or(Error::FunctionalDescriptorNotFound)??;
+                return Ok((descriptor, interface_descriptor));
+            }
+        }
 
         // Get the DFU functional descriptor via GET_DESCRIPTOR standard device request.
-        // https://www.beyondlogic.org/usbnutshell/usb6.shtml#StandardDeviceRequests
-        let descriptor_bytes = interface
-            .control_in(ControlIn {
-                control_type: ControlType::Standard,
-                recipient: Recipient::Device,
-                request: standard_request::GET_DESCRIPTOR,
-                value: ((DFU_FUNCTIONAL_DESCRIPTOR_TYPE as u16) << 8)
-                    | (DFU_FUNCTIONAL_DESCRIPTOR_INDEX as u16),
-                index: 0,
-                length: 9, // DFU functional descriptor is 9 bytes
-            })
-            .await?;
+        let descriptor_bytes = bounded(
+            timeout,
+            DfuRequest::GetDescriptor,
+            transport.descriptor(
+                DFU_FUNCTIONAL_DESCRIPTOR_TYPE,
+                DFU_FUNCTIONAL_DESCRIPTOR_INDEX,
+                0,
+                9, // DFU functional descriptor is 9 bytes
+            ),
+        )
+        .await?;
 
         let descriptor =
             dfu_core::functional_descriptor::FunctionalDescriptor::from_bytes(&descriptor_bytes)
                 .ok_or(Error::FunctionalDescriptorNotFound)??;
+        Ok((descriptor, interface_descriptor))
+    }
 
-        let protocol = DfuProtocol::new("", descriptor.dfu_version)?;
+    /// Whether the interface is in run-time or DFU mode.
+    pub fn mode(&self) -> DfuMode {
+        self.mode
+    }
 
-        Ok(Self {
-            device: Rc::new(device),
-            interface: Rc::new(interface),
-            interface_number,
-            descriptor,
-            protocol,
+    /// The selected alternate setting.
+    pub fn alt_setting(&self) -> u8 {
+        self.alt_setting
+    }
+
+    /// The iInterface string of the selected alternate setting, empty if the device has none.
+    pub fn interface_string(&self) -> &str {
+        &self.interface_string
+    }
+
+    /// Read the idVendor, idProduct and bcdDevice of the device descriptor.
+    pub async fn device_ids(&self) -> Result<DeviceIds, Error> {
+        let device = bounded(
+            self.timeouts.transfer,
+            DfuRequest::GetDescriptor,
+            self.transport.descriptor(descriptor_type::DEVICE, 0, 0, 18),
+        )
+        .await?;
+        descriptor::device_ids(&device).ok_or_else(|| {
+            dfu_core::Error::ResponseTooShort {
+                got: device.len(),
+                expected: 18,
+            }
+            .into()
         })
     }
 
+    /// The transport used to talk to the device.
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Report the progress of downloads and uploads to `progress`.
+    pub fn with_progress(
+        &mut self,
+        progress: impl FnMut(Progress) + MaybeSend + 'static,
+    ) -> &mut Self {
+        self.progress_tracker().set_callback(Box::new(progress));
+        self
+    }
+
+    /// Report the progress of downloads and uploads as a stream of events.
+    ///
+    /// This replaces a callback set with [`with_progress`](Self::with_progress). The stream ends
+    /// when the device is dropped.
+    pub fn progress_stream(&mut self) -> mpsc::UnboundedReceiver<Progress> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.with_progress(move |progress| {
+            // The receiver may have been dropped, progress is then no longer of interest.
+            let _ = sender.unbounded_send(progress);
+        });
+        receiver
+    }
+
+    /// Fail control transfers that take longer than `timeout` with [`Error::Timeout`], 5 s by
+    /// default. It also bounds the USB reset and the requests of
+    /// [`set_alt_setting`](Self::set_alt_setting); opening the device uses the default.
+    pub fn with_transfer_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeouts.transfer = timeout;
+        self
+    }
+
+    /// Fail manifestation with [`Error::Timeout`] if the device is still manifesting after
+    /// `timeout`, 30 s by default, including manifestation driven by [`DfuAsync`] and
+    /// [`DfuSync`]: the first DFU_GETSTATUS past the deadline fails. It also bounds each
+    /// DFU_GETSTATUS while the device manifests, which many devices only answer once they are
+    /// done.
+    pub fn with_manifestation_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeouts.manifestation = timeout;
+        self
+    }
+
+    /// The timeout of a control transfer of `request`.
+    fn timeout(&self, request: u8) -> Duration {
+        if request == DFU_GETSTATUS && self.progress_tracker().is_manifesting() {
+            self.timeouts.manifestation
+        } else {
+            self.timeouts.transfer
+        }
+    }
+
+    /// A handle cancelling downloads and uploads between two blocks, including those driven by
+    /// [`DfuAsync`].
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// Start an operation transferring `total` bytes, which may span several downloads and
+    /// uploads, discarding a cancellation requested before it.
+    fn begin_operation(&self, total: usize) {
+        self.cancel.reset();
+        self.progress_tracker().begin(total);
+    }
+
+    fn progress_tracker(&self) -> MutexGuard<'_, ProgressTracker> {
+        lock(&self.progress)
+    }
+
     /// Wrap device in a sync DFU.
-    pub fn into_sync_dfu(self) -> DfuSync {
+    #[cfg(not(target_family = "wasm"))]
+    pub fn into_sync_dfu(self) -> DfuSync<T> {
         DfuSync::new(self)
     }
 
     /// Wrap device in an async DFU.
-    pub fn into_async_dfu(self) -> DfuAsync {
+    pub fn into_async_dfu(self) -> DfuAsync<T> {
         DfuAsync::new(self)
     }
 
@@ -115,33 +504,58 @@ impl DfuCrossUsb {
         value: u16,
         buffer: &mut [u8],
     ) -> impl Future<Output = Result<usize, Error>> + Send {
-        let (control_type, recipient) = split_request_type(request_type);
-
-        let (tx, rx) = oneshot::channel::<Result<Vec<u8>, cross_usb::usb::Error>>();
-        {
-            let interface = self.interface.clone();
-            let interface_number = self.interface_number as u16;
-            let buffer_len = buffer.len() as u16;
-            spawn_local(async move {
-                let bytes = interface
-                    .control_in(ControlIn {
-                        control_type,
-                        index: interface_number,
-                        recipient,
-                        request,
-                        value,
-                        length: buffer_len,
-                    })
-                    .await;
-                tx.send(bytes)
-                    .expect("The oneshot receiver was dropped unexpectedly");
-            });
-        }
+        let transport = self.transport.clone();
+        let progress = self.progress.clone();
+        let interface_number = self.interface_number as u16;
+        let buffer_len = buffer.len() as u16;
+        let cancel = self.cancel.clone();
+        let starts_transfer = self.progress_tracker().starts_transfer();
+        let timeout = self.timeout(request);
+        let manifestation_expired = request == DFU_GETSTATUS
+            && self
+                .progress_tracker()
+                .manifesting_for()
+                .is_some_and(|elapsed| elapsed > self.timeouts.manifestation);
+        let last_block = self.last_block.clone();
+        let bytes = runtime::spawn(async move {
+            if manifestation_expired {
+                return Err(Error::Timeout {
+                    request: DfuRequest::GetStatus,
+                });
+            }
+            if request == DFU_UPLOAD && starts_transfer {
+                cancel.reset();
+            } else if request == DFU_UPLOAD && cancel.take() {
+                return Err(cancel::abort(&*transport, interface_number, timeout).await);
+            }
+            let transfer =
+                transport.control_in(request_type, request, value, interface_number, buffer_len);
+            let bytes = runtime::timeout(timeout, transfer)
+                .await?
+                .ok_or(Error::Timeout {
+                    request: request.into(),
+                })??;
+            lock(&progress).control_in(request, value, buffer_len, &bytes);
+            match request {
+                DFU_UPLOAD => *lock(&last_block) = Some(value),
+                DFU_GETSTATUS => {
+                    let block = {
+                        let mut last_block = lock(&last_block);
+                        // A device still busy with the block reports its error on a later poll.
+                        match bytes.get(4).map(|&state| State::from(state)) {
+                            Some(State::DfuDnbusy | State::DfuDnloadSync) => *last_block,
+                            _ => last_block.take(),
+                        }
+                    };
+                    status::check(&*transport, block, &bytes, timeout).await?;
+                }
+                _ => {}
+            }
+            Ok::<_, Error>(bytes)
+        });
 
         async move {
-            let bytes = rx
-                .await
-                .expect("The control in future should not be cancelled")?;
+            let bytes = bytes.await?;
             let len = std::cmp::min(bytes.len(), buffer.len());
             buffer[..len].copy_from_slice(&bytes[..len]);
             Ok(len)
@@ -155,57 +569,64 @@ impl DfuCrossUsb {
         value: u16,
         buffer: &[u8],
     ) -> impl Future<Output = Result<usize, Error>> + Send {
-        let (control_type, recipient) = split_request_type(request_type);
-
-        let (tx, rx) = oneshot::channel::<Result<usize, cross_usb::usb::Error>>();
-        {
-            let interface = self.interface.clone();
-            let interface_number = self.interface_number as u16;
-            let buffer = buffer.to_vec();
-            spawn_local(async move {
-                let bytes_written = interface
-                    .control_out(ControlOut {
-                        control_type,
-                        index: interface_number,
-                        recipient,
-                        request,
-                        value,
-                        data: &buffer,
-                    })
-                    .await;
-                tx.send(bytes_written)
-                    .expect("The oneshot receiver was dropped unexpectedly");
-            });
-        }
+        let transport = self.transport.clone();
+        let progress = self.progress.clone();
+        let interface_number = self.interface_number as u16;
+        let buffer = buffer.to_vec();
+        let cancel = self.cancel.clone();
+        let starts_transfer = self.progress_tracker().starts_transfer();
+        let timeout = self.timeout(request);
+        let last_block = self.last_block.clone();
+        runtime::spawn(async move {
+            if request == DFU_DNLOAD && starts_transfer {
+                cancel.reset();
+            } else if request == DFU_DNLOAD && cancel.take() {
+                return Err(cancel::abort(&*transport, interface_number, timeout).await);
+            }
+            let transfer =
+                transport.control_out(request_type, request, value, interface_number, &buffer);
+            let written = runtime::timeout(timeout, transfer)
+                .await?
+                .ok_or(Error::Timeout {
+                    request: request.into(),
+                })??;
+            lock(&progress).control_out(request, value, &buffer);
+            *lock(&last_block) = match request {
+                DFU_DNLOAD => Some(value),
+                _ => None,
+            };
+            Ok(written)
+        })
+    }
 
-        async move {
-            let bytes_written = rx
-                .await
-                .expect("The control in future should not be cancelled")?;
-            Ok(bytes_written)
-        }
+    fn usb_reset(&self) -> impl Future<Output = Result<(), Error>> + Send {
+        let transport = self.transport.clone();
+        let timeout = self.timeouts.transfer;
+        runtime::spawn(
+            async move { bounded(timeout, DfuRequest::UsbReset, transport.reset()).await },
+        )
     }
 }
 
-fn split_request_type(request_type: u8) -> (ControlType, Recipient) {
-    (
-        match request_type >> 5 & 0x03 {
-            0 => ControlType::Standard,
-            1 => ControlType::Class,
-            2 => ControlType::Vendor,
-            _ => ControlType::Standard,
-        },
-        match request_type & 0x1f {
-            0 => Recipient::Device,
-            1 => Recipient::Interface,
-            2 => Recipient::Endpoint,
-            3 => Recipient::Other,
-            _ => Recipient::Device,
-        },
-    )
+/// Run a request that is not sent with `read_control` or `write_control`, e.g. while opening
+/// the device, failing with [`Error::Timeout`] if it takes longer than `timeout`.
+async fn bounded<R>(
+    timeout: Duration,
+    request: DfuRequest,
+    future: impl Future<Output = Result<R, Error>>,
+) -> Result<R, Error> {
+    runtime::timeout(timeout, future)
+        .await?
+        .ok_or(Error::Timeout { request })?
+}
+
+/// Lock a mutex, a panicking progress callback leaves the tracker usable.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
 }
 
-impl dfu_core::DfuIo for DfuCrossUsb {
+#[cfg(not(target_family = "wasm"))]
+impl<T: UsbTransport + 'static> dfu_core::DfuIo for DfuCrossUsb<T> {
     type Read = usize;
     type Write = usize;
     type Reset = ();
@@ -233,7 +654,7 @@ impl dfu_core::DfuIo for DfuCrossUsb {
     }
 
     fn usb_reset(&self) -> Result<Self::Reset, Self::Error> {
-        Ok(block_on(self.device.reset())?)
+        block_on(self.usb_reset())
     }
 
     fn protocol(&self) -> &DfuProtocol<Self::MemoryLayout> {
@@ -245,7 +666,7 @@ impl dfu_core::DfuIo for DfuCrossUsb {
     }
 }
 
-impl dfu_core::asynchronous::DfuAsyncIo for DfuCrossUsb {
+impl<T: UsbTransport + 'static> dfu_core::asynchronous::DfuAsyncIo for DfuCrossUsb<T> {
     type Read = usize;
     type Write = usize;
     type Reset = ();
@@ -273,15 +694,7 @@ impl dfu_core::asynchronous::DfuAsyncIo for DfuCrossUsb {
     }
 
     fn usb_reset(&self) -> impl Future<Output = Result<Self::Reset, Self::Error>> + Send {
-        let (tx, rx) = oneshot::channel();
-        let device = self.device.clone();
-        spawn_local(async move {
-            let res = device.reset().await;
-            tx.send(res)
-                .expect("Oneshot received was dropped unexpectedly");
-        });
-
-        async move { Ok(rx.await.expect("Oneshot sender was dropped unexpectedly")?) }
+        self.usb_reset()
     }
 
     fn protocol(&self) -> &DfuProtocol<Self::MemoryLayout> {
@@ -0,0 +1,94 @@
+//! The memory of a DfuSe alternate setting, as described by its iInterface string
+//! (UM0424, Section 10.3.2), e.g. "@Internal Flash /0x08000000/04*016Kg,01*064Kg".
+//!
+//! `dfu_core` parses the address and page sizes, the letter ending each group of pages holds
+//! its permissions: `a` readable, `b` erasable, `d` writable or a sum of them.
+
+use dfu_core::DfuProtocol;
+use dfu_core::memory_layout::MemoryLayout;
+
+/// Permission bit of erasable pages.
+#[cfg(feature = "sim")]
+const ERASABLE: u8 = 1 << 1;
+/// Permission bit of writable pages, `a` (1) is readable.
+const WRITABLE: u8 = 1 << 2;
+
+/// A page of the memory of a DfuSe alternate setting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Page {
+    pub address: u32,
+    pub size: u32,
+    permissions: u8,
+}
+
+impl Page {
+    pub(crate) fn end(&self) -> u64 {
+        self.address as u64 + self.size as u64
+    }
+
+    pub(crate) fn contains(&self, address: u64) -> bool {
+        (self.address as u64..self.end()).contains(&address)
+    }
+
+    #[cfg(feature = "sim")]
+    pub(crate) fn is_erasable(&self) -> bool {
+        self.permissions & ERASABLE != 0
+    }
+
+    pub(crate) fn is_writable(&self) -> bool {
+        self.permissions & WRITABLE != 0
+    }
+}
+
+/// The pages of a DfuSe alternate setting, empty for DFU 1.1.
+pub(crate) fn pages(interface_string: &str, protocol: &DfuProtocol<MemoryLayout>) -> Vec<Page> {
+    let DfuProtocol::Dfuse {
+        address,
+        memory_layout,
+    } = protocol
+    else {
+        return Vec::new();
+    };
+    let groups = interface_string
+        .rsplit_once('/')
+        .map_or("", |(_, groups)| groups);
+    let permissions = groups.split(',').flat_map(|group| {
+        let count = group
+            .split_once('*')
+            .and_then(|(count, _)| count.trim().parse().ok())
+            .unwrap_or(0);
+        let permissions = match group.trim().chars().last() {
+            Some(letter @ 'a'..='g') => letter as u8 - b'a' + 1,
+            _ => 0,
+        };
+        std::iter::repeat_n(permissions, count)
+    });
+
+    let mut pages = Vec::new();
+    let mut page_address = *address as u64;
+    for (&size, permissions) in memory_layout.as_slice().iter().zip(permissions) {
+        let Ok(address) = u32::try_from(page_address) else {
+            break;
+        };
+        pages.push(Page {
+            address,
+            size,
+            permissions,
+        });
+        page_address += size as u64;
+    }
+    pages
+}
+
+/// The first address of `length` bytes from `address` that is not in a writable page.
+pub(crate) fn first_unwritable(pages: &[Page], address: u32, length: usize) -> Option<u32> {
+    let end = address as u64 + length as u64;
+    let mut address = address as u64;
+    while address < end {
+        match pages.iter().find(|page| page.contains(address)) {
+            Some(page) if page.is_writable() => address = page.end(),
+            _ => return Some(address as u32),
+        }
+    }
+    None
+}
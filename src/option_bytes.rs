@@ -0,0 +1,397 @@
+//! STM32 option bytes, exposed by the system bootloader as a DfuSe alternate setting, e.g.
+//! "@Option Bytes  /0x1FFFF800/01*016 e" (AN3156, Section 4).
+//!
+//! The layouts follow the reference manuals: RM0091 and RM0316 for STM32F0 and STM32F3,
+//! RM0008 for STM32F1 and RM0033 and RM0090 for STM32F2 and STM32F4.
+
+use crate::{DfuCrossUsb, Error, UsbTransport, descriptor};
+use dfu_core::State;
+use std::fmt;
+use std::str::FromStr;
+
+/// The prefix of the iInterface string of the option bytes alternate setting.
+const OPTION_BYTES_NAME: &str = "@Option Bytes";
+
+/// An STM32 series whose option bytes can be decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stm32Family {
+    F0,
+    F1,
+    F2,
+    F3,
+    F4,
+}
+
+/// The layout of the option bytes of a series.
+enum Layout {
+    /// Pairs of a byte and its complement at 0x1FFFF800: RDP, USER, Data0, Data1, WRP0-3.
+    Complemented { level_2: bool },
+    /// USER and RDP at 0x1FFFC000, the nWRP bits at 0x1FFFC008.
+    Sectors,
+}
+
+impl Stm32Family {
+    /// The address of the option bytes.
+    pub fn address(&self) -> u32 {
+        match self.layout() {
+            Layout::Complemented { .. } => 0x1fff_f800,
+            Layout::Sectors => 0x1fff_c000,
+        }
+    }
+
+    fn layout(&self) -> Layout {
+        match self {
+            Self::F0 | Self::F3 => Layout::Complemented { level_2: true },
+            Self::F1 => Layout::Complemented { level_2: false },
+            Self::F2 | Self::F4 => Layout::Sectors,
+        }
+    }
+
+    /// The offsets of the RDP and USER bytes.
+    fn offsets(&self) -> (usize, usize) {
+        match self.layout() {
+            Layout::Complemented { .. } => (0, 2),
+            Layout::Sectors => (1, 0),
+        }
+    }
+
+    /// The bits of the USER byte for the watchdog, Stop and Standby options.
+    fn user_bits(&self) -> [u8; 3] {
+        match self.layout() {
+            Layout::Complemented { .. } => [1 << 0, 1 << 1, 1 << 2],
+            Layout::Sectors => [1 << 5, 1 << 6, 1 << 7],
+        }
+    }
+}
+
+impl fmt::Display for Stm32Family {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "STM32{self:?}")
+    }
+}
+
+impl FromStr for Stm32Family {
+    type Err = OptionBytesError;
+
+    /// Parse a series or part name, e.g. "STM32F1" or "STM32F103C8".
+    fn from_str(name: &str) -> Result<Self, OptionBytesError> {
+        let upper = name.trim().to_ascii_uppercase();
+        let series = upper.strip_prefix("STM32").unwrap_or(&upper);
+        match series.get(..2) {
+            Some("F0") => Ok(Self::F0),
+            Some("F1") => Ok(Self::F1),
+            Some("F2") => Ok(Self::F2),
+            Some("F3") => Ok(Self::F3),
+            Some("F4") => Ok(Self::F4),
+            _ => Err(OptionBytesError::UnsupportedFamily(name.to_string())),
+        }
+    }
+}
+
+/// Option bytes that cannot be read, decoded or written.
+#[derive(Debug, thiserror::Error)]
+pub enum OptionBytesError {
+    #[error("The device has no option bytes alternate setting")]
+    NotFound,
+    #[error("Option bytes of {0} are not supported")]
+    UnsupportedFamily(String),
+    #[error("The option bytes at {address:#010x} are not those of {family}")]
+    UnexpectedAddress { family: Stm32Family, address: u32 },
+    #[error("{family} has {expected} bytes of option bytes, got {actual}")]
+    InvalidLength {
+        family: Stm32Family,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("The option byte at offset {offset} does not match its complement")]
+    ComplementMismatch { offset: usize },
+    #[error("{family} does not support read protection {level:?}")]
+    UnsupportedLevel {
+        family: Stm32Family,
+        level: ReadProtection,
+    },
+    #[error("Read protection level 2 cannot be undone, confirm it with confirm_irreversible")]
+    Irreversible,
+}
+
+/// The read protection (RDP) level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadProtection {
+    /// Memory can be read and debugged.
+    Level0,
+    /// Memory cannot be read by the debugger or the bootloader. Going back to level 0
+    /// mass erases the flash.
+    Level1,
+    /// The debugger and the bootloader are disabled for good.
+    Level2,
+}
+
+/// The USER option bits common to the supported series.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserOptions {
+    /// WDG_SW: the watchdog is started by software rather than at reset.
+    pub software_watchdog: bool,
+    /// nRST_STOP cleared: entering Stop mode resets the device.
+    pub reset_on_stop: bool,
+    /// nRST_STDBY cleared: entering Standby mode resets the device.
+    pub reset_on_standby: bool,
+}
+
+/// The decoded option bytes of an STM32 device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OptionBytes {
+    family: Stm32Family,
+    bytes: Vec<u8>,
+}
+
+impl OptionBytes {
+    /// The number of bytes of the option bytes of the supported series.
+    const LENGTH: usize = 16;
+
+    /// Decode the option bytes `bytes` of `family`, read at `address`.
+    pub fn decode(
+        family: Stm32Family,
+        address: u32,
+        bytes: &[u8],
+    ) -> Result<Self, OptionBytesError> {
+        if address != family.address() {
+            return Err(OptionBytesError::UnexpectedAddress { family, address });
+        }
+        if bytes.len() != Self::LENGTH {
+            return Err(OptionBytesError::InvalidLength {
+                family,
+                expected: Self::LENGTH,
+                actual: bytes.len(),
+            });
+        }
+        if let Layout::Complemented { .. } = family.layout()
+            && let Some(pair) = bytes.chunks(2).position(|pair| pair[0] != !pair[1])
+        {
+            return Err(OptionBytesError::ComplementMismatch { offset: pair * 2 });
+        }
+        Ok(Self {
+            family,
+            bytes: bytes.to_vec(),
+        })
+    }
+
+    /// The series the option bytes were decoded for.
+    pub fn family(&self) -> Stm32Family {
+        self.family
+    }
+
+    /// The raw option bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The read protection level.
+    pub fn read_protection(&self) -> ReadProtection {
+        let (rdp, _) = self.family.offsets();
+        match (self.family.layout(), self.bytes[rdp]) {
+            (Layout::Complemented { level_2: false }, 0xa5) => ReadProtection::Level0,
+            (Layout::Complemented { level_2: false }, _) => ReadProtection::Level1,
+            (_, 0xaa) => ReadProtection::Level0,
+            (_, 0xcc) => ReadProtection::Level2,
+            _ => ReadProtection::Level1,
+        }
+    }
+
+    /// Set the read protection level.
+    pub fn set_read_protection(&mut self, level: ReadProtection) -> Result<(), OptionBytesError> {
+        let value = match (self.family.layout(), level) {
+            (Layout::Complemented { level_2: false }, ReadProtection::Level0) => 0xa5,
+            (Layout::Complemented { level_2: false }, ReadProtection::Level2) => {
+                return Err(OptionBytesError::UnsupportedLevel {
+                    family: self.family,
+                    level,
+                });
+            }
+            (_, ReadProtection::Level0) => 0xaa,
+            (_, ReadProtection::Level1) => 0x00,
+            (_, ReadProtection::Level2) => 0xcc,
+        };
+        let (rdp, _) = self.family.offsets();
+        self.set(rdp, value);
+        Ok(())
+    }
+
+    /// The write protected sectors, or groups of pages, as a bit mask: bit `n` is set if
+    /// sector or group `n` is protected.
+    pub fn write_protection(&self) -> u32 {
+        match self.family.layout() {
+            Layout::Complemented { .. } => {
+                let wrp = [
+                    self.bytes[8],
+                    self.bytes[10],
+                    self.bytes[12],
+                    self.bytes[14],
+                ];
+                !u32::from_le_bytes(wrp)
+            }
+            Layout::Sectors => !u16::from_le_bytes([self.bytes[8], self.bytes[9]]) as u32 & 0xfff,
+        }
+    }
+
+    /// Write protect the sectors, or groups of pages, set in `mask`.
+    pub fn set_write_protection(&mut self, mask: u32) {
+        let wrp = (!mask).to_le_bytes();
+        match self.family.layout() {
+            Layout::Complemented { .. } => {
+                for (i, byte) in wrp.into_iter().enumerate() {
+                    self.set(8 + 2 * i, byte);
+                }
+            }
+            Layout::Sectors => {
+                self.bytes[8] = wrp[0];
+                self.bytes[9] = self.bytes[9] & !0x0f | wrp[1] & 0x0f;
+            }
+        }
+    }
+
+    /// The USER option bits.
+    pub fn user(&self) -> UserOptions {
+        let (_, user) = self.family.offsets();
+        let [watchdog, stop, standby] = self.family.user_bits();
+        let user = self.bytes[user];
+        UserOptions {
+            software_watchdog: user & watchdog != 0,
+            reset_on_stop: user & stop == 0,
+            reset_on_standby: user & standby == 0,
+        }
+    }
+
+    /// Set the USER option bits, leaving the other bits of the USER byte alone.
+    pub fn set_user(&mut self, options: UserOptions) {
+        let (_, offset) = self.family.offsets();
+        let [watchdog, stop, standby] = self.family.user_bits();
+        let mut user = self.bytes[offset] & !(watchdog | stop | standby);
+        if options.software_watchdog {
+            user |= watchdog;
+        }
+        if !options.reset_on_stop {
+            user |= stop;
+        }
+        if !options.reset_on_standby {
+            user |= standby;
+        }
+        self.set(offset, user);
+    }
+
+    /// Set the byte at `offset` and, in complemented layouts, its complement.
+    fn set(&mut self, offset: usize, value: u8) {
+        self.bytes[offset] = value;
+        if let Layout::Complemented { .. } = self.family.layout() {
+            self.bytes[offset + 1] = !value;
+        }
+    }
+}
+
+/// A pending write of option bytes, see [`DfuCrossUsb::write_option_bytes`]. Nothing is
+/// written unless it is confirmed.
+#[must_use = "option bytes are only written once the write is confirmed"]
+pub struct OptionBytesWrite<'a, T: UsbTransport + 'static> {
+    device: &'a mut DfuCrossUsb<T>,
+    bytes: OptionBytes,
+}
+
+impl<T: UsbTransport + 'static> OptionBytesWrite<'_, T> {
+    /// The option bytes that will be written.
+    pub fn option_bytes(&self) -> &OptionBytes {
+        &self.bytes
+    }
+
+    /// Whether the write sets read protection level 2, which cannot be undone.
+    pub fn is_irreversible(&self) -> bool {
+        self.bytes.read_protection() == ReadProtection::Level2
+    }
+
+    /// Write the option bytes, refusing read protection level 2 with
+    /// [`OptionBytesError::Irreversible`].
+    pub async fn confirm(self) -> Result<(), Error> {
+        if self.is_irreversible() {
+            return Err(OptionBytesError::Irreversible.into());
+        }
+        self.confirm_irreversible().await
+    }
+
+    /// Write the option bytes, including read protection level 2.
+    pub async fn confirm_irreversible(self) -> Result<(), Error> {
+        let alt_setting = self.device.option_bytes_alt_setting().await?;
+        self.device.set_alt_setting(alt_setting).await?;
+        self.device.expect_idle().await?;
+        self.device
+            .dfuse_send(self.bytes.family.address(), &self.bytes.bytes)
+            .await?;
+        // The device reloads its option bytes with a reset once they are written and may not
+        // report the status of the last block it accepted.
+        match self.device.wait_for_state(State::DfuDnloadIdle).await {
+            Err(Error::WebUsb(_)) => Ok(()),
+            result => result.map(drop),
+        }
+    }
+}
+
+impl<T: UsbTransport + 'static> DfuCrossUsb<T> {
+    /// The alternate setting of the option bytes of an STM32 system bootloader, found by its
+    /// iInterface string.
+    pub async fn option_bytes_alt_setting(&self) -> Result<u8, Error> {
+        let configuration = self.transport.configuration_descriptor(0).await?;
+        for interface in descriptor::interfaces(&configuration) {
+            let interface = interface.descriptor;
+            if interface.interface_number != self.interface_number
+                || interface.interface_string == 0
+            {
+                continue;
+            }
+            let name = self
+                .transport
+                .string_descriptor(interface.interface_string)
+                .await?;
+            if name.trim_start().starts_with(OPTION_BYTES_NAME) {
+                return Ok(interface.alternate_setting);
+            }
+        }
+        Err(OptionBytesError::NotFound.into())
+    }
+
+    /// Read and decode the option bytes of an STM32 device of `family`.
+    ///
+    /// The option bytes alternate setting is selected for the upload, the previous alternate
+    /// setting is selected again afterwards.
+    pub async fn read_option_bytes(&mut self, family: Stm32Family) -> Result<OptionBytes, Error> {
+        let previous = self.alt_setting;
+        self.set_alt_setting(self.option_bytes_alt_setting().await?)
+            .await?;
+        let result = self.upload_option_bytes(family).await;
+        self.set_alt_setting(previous).await?;
+        result
+    }
+
+    async fn upload_option_bytes(&self, family: Stm32Family) -> Result<OptionBytes, Error> {
+        let dfu_core::DfuProtocol::Dfuse {
+            address,
+            memory_layout,
+        } = &self.protocol
+        else {
+            return Err(Error::DfuseNotSupported);
+        };
+        let length = memory_layout.as_slice().iter().sum::<u32>() as usize;
+        self.expect_idle().await?;
+        let bytes = self.dfuse_read(*address, length).await?;
+        Ok(OptionBytes::decode(family, *address, &bytes)?)
+    }
+
+    /// Prepare writing option bytes, e.g. read with
+    /// [`read_option_bytes`](Self::read_option_bytes) and modified.
+    ///
+    /// Nothing is written until the returned [`OptionBytesWrite`] is confirmed. The device
+    /// resets to load the new option bytes and has to be opened again. Lowering the read
+    /// protection from level 1 to level 0 mass erases the flash.
+    pub fn write_option_bytes(&mut self, bytes: OptionBytes) -> OptionBytesWrite<'_, T> {
+        OptionBytesWrite {
+            device: self,
+            bytes,
+        }
+    }
+}
@@ -0,0 +1,370 @@
+//! Flash plans: the pages a DfuSe download erases and the segments it writes, computed before
+//! anything is sent to the device.
+//!
+//! A plan can be narrowed to the pages whose contents change, compared either with the memory
+//! of the device or with the CRC-32 of the pages of a previous plan.
+
+use crate::crc::Crc32;
+use crate::firmware::{FileError, Segment, SparseFirmware, decode_hex};
+use crate::{DfuCrossUsb, DownloadOptions, Error, UsbTransport};
+use std::fmt;
+use std::iter;
+use std::str::FromStr;
+
+/// The first line of a serialized plan.
+const HEADER: &str = "dfu-cross-usb flash plan";
+/// The number of bytes per `write` line of a serialized plan.
+const BYTES_PER_LINE: usize = 32;
+
+/// A page of DfuSe memory erased by a [`FlashPlan`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErasePage {
+    /// The start address of the page.
+    pub address: u32,
+    /// The number of bytes of the page.
+    pub size: u32,
+}
+
+/// The CRC-32 of a page of DfuSe memory once a [`FlashPlan`] was executed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageHash {
+    /// The start address of the page.
+    pub address: u32,
+    /// The CRC-32 of the contents of the page.
+    pub crc: u32,
+}
+
+/// A DfuSe download planned by [`DfuCrossUsb::plan`]: the pages to erase, each once even if
+/// several segments share it, and the segments to write.
+///
+/// A plan serializes to a line based text format with [`Display`](fmt::Display) and parses
+/// back with [`FromStr`], e.g. to be reviewed or executed later.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlashPlan {
+    alt_setting: u8,
+    /// The address the device starts the firmware from when it leaves DFU mode.
+    start: Option<u32>,
+    erases: Vec<ErasePage>,
+    firmware: SparseFirmware,
+}
+
+impl FlashPlan {
+    /// The alternate setting whose memory the plan is for.
+    pub fn alt_setting(&self) -> u8 {
+        self.alt_setting
+    }
+
+    /// The pages to erase, sorted by address.
+    pub fn erases(&self) -> &[ErasePage] {
+        &self.erases
+    }
+
+    /// The segments to write.
+    pub fn firmware(&self) -> &SparseFirmware {
+        &self.firmware
+    }
+
+    /// The number of bytes of the pages to erase.
+    pub fn erase_bytes(&self) -> u64 {
+        self.erases.iter().map(|page| page.size as u64).sum()
+    }
+
+    /// The number of bytes to write.
+    pub fn write_bytes(&self) -> usize {
+        self.firmware.len()
+    }
+
+    /// The contents of `page` once the plan was executed: the firmware, erased bytes (0xff)
+    /// elsewhere. Only called for pages checked against the memory layout, as a parsed plan
+    /// may claim pages of any size.
+    fn page_contents(&self, page: &ErasePage) -> Vec<u8> {
+        let mut contents = vec![0xff; page.size as usize];
+        for (address, data) in self.page_segments(page) {
+            let offset = (address - page.address) as usize;
+            contents[offset..offset + data.len()].copy_from_slice(data);
+        }
+        contents
+    }
+
+    /// The CRC-32 of the contents of `page` once the plan was executed, without holding the
+    /// page in memory.
+    fn page_crc(&self, page: &ErasePage) -> u32 {
+        let mut crc = Crc32::new();
+        let mut next = page.address as u64;
+        for (address, data) in self.page_segments(page) {
+            crc.update(iter::repeat_n(0xff, (address as u64 - next) as usize));
+            crc.update(data.iter().copied());
+            next = address as u64 + data.len() as u64;
+        }
+        let end = page.address as u64 + page.size as u64;
+        crc.update(iter::repeat_n(0xff, (end - next) as usize));
+        crc.finish()
+    }
+
+    /// The parts of the segments within `page`, as `(address, data)`.
+    fn page_segments<'a>(&'a self, page: &ErasePage) -> impl Iterator<Item = (u32, &'a [u8])> {
+        let start = page.address as u64;
+        let end = start + page.size as u64;
+        self.firmware.segments().iter().filter_map(move |segment| {
+            let from = start.max(segment.address as u64);
+            let to = end.min(segment.end());
+            let offset = (from - segment.address as u64) as usize;
+            (from < to).then(|| {
+                (
+                    from as u32,
+                    &segment.data[offset..offset + (to - from) as usize],
+                )
+            })
+        })
+    }
+
+    /// The CRC-32 of every page once the plan was executed, to be kept and compared with the
+    /// plan of the next update by [`without_unchanged`](Self::without_unchanged).
+    pub fn page_hashes(&self) -> Vec<PageHash> {
+        self.erases
+            .iter()
+            .map(|page| PageHash {
+                address: page.address,
+                crc: self.page_crc(page),
+            })
+            .collect()
+    }
+
+    /// The plan without the pages whose contents match `previous`, the page hashes of the
+    /// plan last executed on the device.
+    pub fn without_unchanged(&self, previous: &[PageHash]) -> FlashPlan {
+        self.retain_pages(|page| {
+            !previous
+                .iter()
+                .any(|hash| hash.address == page.address && hash.crc == self.page_crc(page))
+        })
+    }
+
+    /// The plan with only the pages for which `changed` returns true. The start address is
+    /// kept, so the device still leaves DFU mode.
+    fn retain_pages(&self, mut changed: impl FnMut(&ErasePage) -> bool) -> FlashPlan {
+        let mut erases = Vec::new();
+        let mut segments = Vec::new();
+        for page in &self.erases {
+            if !changed(page) {
+                continue;
+            }
+            erases.push(*page);
+            segments.extend(self.page_segments(page).map(|(address, data)| Segment {
+                address,
+                data: data.to_vec(),
+            }));
+        }
+        FlashPlan {
+            alt_setting: self.alt_setting,
+            start: self.start,
+            erases,
+            // Pages do not overlap, neither do their parts of the segments.
+            firmware: SparseFirmware::from_segments(segments).unwrap_or_default(),
+        }
+    }
+
+    /// A one line summary for users, e.g. "erasing 12 pages (192 KiB), writing 384 KiB".
+    pub fn summary(&self) -> String {
+        let pages = match self.erases.len() {
+            1 => "1 page".to_string(),
+            count => format!("{count} pages"),
+        };
+        format!(
+            "erasing {pages} ({}), writing {}",
+            kib(self.erase_bytes()),
+            kib(self.write_bytes() as u64)
+        )
+    }
+}
+
+/// A number of bytes in KiB, rounded up.
+fn kib(bytes: u64) -> String {
+    format!("{} KiB", bytes.div_ceil(1024))
+}
+
+impl fmt::Display for FlashPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{HEADER}")?;
+        writeln!(f, "alt {}", self.alt_setting)?;
+        if let Some(start) = self.start {
+            writeln!(f, "start {start:#010x}")?;
+        }
+        for page in &self.erases {
+            writeln!(f, "erase {:#010x} {:#x}", page.address, page.size)?;
+        }
+        for segment in self.firmware.segments() {
+            for (i, chunk) in segment.data.chunks(BYTES_PER_LINE).enumerate() {
+                let address = segment.address.wrapping_add((i * BYTES_PER_LINE) as u32);
+                write!(f, "write {address:#010x} ")?;
+                for byte in chunk {
+                    write!(f, "{byte:02x}")?;
+                }
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for FlashPlan {
+    type Err = FileError;
+
+    /// Parse a plan serialized with [`Display`](fmt::Display).
+    fn from_str(text: &str) -> Result<Self, FileError> {
+        let mut lines = text.lines().enumerate();
+        let invalid = |line: usize, reason| FileError::InvalidRecord {
+            line: line + 1,
+            reason,
+        };
+        match lines.next() {
+            Some((_, line)) if line.trim() == HEADER => {}
+            _ => return Err(invalid(0, "missing flash plan header")),
+        }
+
+        let mut alt_setting = None;
+        let mut start = None;
+        let mut erases = Vec::new();
+        let mut segments = Vec::new();
+        for (i, line) in lines {
+            let mut fields = line.split_whitespace();
+            let (Some(kind), Some(value)) = (fields.next(), fields.next()) else {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                return Err(invalid(i, "missing field"));
+            };
+            let argument = fields.next();
+            if fields.next().is_some() {
+                return Err(invalid(i, "trailing field"));
+            }
+            match (kind, argument) {
+                ("alt", None) if alt_setting.is_none() => {
+                    alt_setting = Some(value.parse().map_err(|_| invalid(i, "invalid number"))?);
+                }
+                ("start", None) if start.is_none() => {
+                    start = Some(parse_hex(value).ok_or_else(|| invalid(i, "invalid address"))?);
+                }
+                ("erase", Some(size)) => {
+                    let address = parse_hex(value).ok_or_else(|| invalid(i, "invalid address"))?;
+                    // A page ends within the 32-bit address space.
+                    let size = parse_hex(size)
+                        .filter(|&size| size > 0 && address.checked_add(size - 1).is_some())
+                        .ok_or_else(|| invalid(i, "invalid size"))?;
+                    erases.push(ErasePage { address, size });
+                }
+                ("write", Some(data)) => segments.push(Segment {
+                    address: parse_hex(value).ok_or_else(|| invalid(i, "invalid address"))?,
+                    data: decode_hex(data).ok_or_else(|| invalid(i, "invalid hex digits"))?,
+                }),
+                _ => return Err(invalid(i, "unsupported record")),
+            }
+        }
+
+        erases.sort_by_key(|page| page.address);
+        erases.dedup();
+        Ok(Self {
+            alt_setting: alt_setting.ok_or(FileError::InvalidRecord {
+                line: text.lines().count(),
+                reason: "missing alternate setting",
+            })?,
+            start,
+            erases,
+            firmware: SparseFirmware::from_segments(segments)?,
+        })
+    }
+}
+
+fn parse_hex(value: &str) -> Option<u32> {
+    u32::from_str_radix(value.strip_prefix("0x")?, 16).ok()
+}
+
+impl<T: UsbTransport + 'static> DfuCrossUsb<T> {
+    /// Plan the download of sparse firmware to the DfuSe memory of the alternate setting,
+    /// without sending anything to the device.
+    ///
+    /// Fails with [`Error::DfuseNotSupported`] on DFU 1.1 devices, which have no pages, and with
+    /// [`Error::AddressOutOfRange`] if a segment lies outside the memory.
+    pub fn plan(&self, firmware: &SparseFirmware) -> Result<FlashPlan, Error> {
+        if !self.is_dfuse() {
+            return Err(Error::DfuseNotSupported);
+        }
+        Ok(FlashPlan {
+            alt_setting: self.alt_setting,
+            start: firmware.segments().first().map(|segment| segment.address),
+            erases: self.dfuse_erase_pages(&firmware.images())?,
+            firmware: firmware.clone(),
+        })
+    }
+
+    /// Narrow a plan to the pages whose contents differ from the memory of the device, which is
+    /// read back page by page with DFU_UPLOAD. Pages that already match are neither erased nor
+    /// written.
+    ///
+    /// The alternate setting of the plan is selected and the plan checked against its memory
+    /// layout first, like [`execute_plan`](Self::execute_plan). Devices that cannot upload are
+    /// refused with [`Error::VerifyNotSupported`].
+    pub async fn diff_plan(&mut self, plan: &FlashPlan) -> Result<FlashPlan, Error> {
+        self.select_plan(plan).await?;
+        if !self.descriptor.can_upload {
+            return Err(Error::VerifyNotSupported);
+        }
+        self.expect_idle().await?;
+        let mut unchanged = Vec::new();
+        for page in &plan.erases {
+            let contents = self.dfuse_read(page.address, page.size as usize).await?;
+            if contents == plan.page_contents(page) {
+                unchanged.push(page.address);
+            }
+        }
+        Ok(plan.retain_pages(|page| !unchanged.contains(&page.address)))
+    }
+
+    /// Execute a plan like [`download_sparse`](Self::download_sparse): erase its pages, write
+    /// its segments, optionally verify them and leave DFU mode from its start address, even
+    /// if a narrowed plan has nothing left to write.
+    ///
+    /// The alternate setting of the plan is selected first. Before anything is erased, a plan
+    /// whose erases are not pages of the memory layout, or which writes outside its erased
+    /// pages, e.g. a parsed plan made for another device, is refused with
+    /// [`Error::PlanMismatch`].
+    pub async fn execute_plan(
+        &mut self,
+        plan: &FlashPlan,
+        options: &DownloadOptions,
+    ) -> Result<(), Error> {
+        self.select_plan(plan).await?;
+        if options.verify && !self.can_verify() {
+            return Err(Error::VerifyNotSupported);
+        }
+
+        self.begin_operation(plan.write_bytes());
+        let result = self
+            .dfuse_download_pages(&plan.erases, &plan.firmware.images(), plan.start, options)
+            .await;
+        self.progress_tracker().end();
+        result
+    }
+
+    /// Select the alternate setting of a plan and check the plan against its memory layout.
+    async fn select_plan(&mut self, plan: &FlashPlan) -> Result<(), Error> {
+        self.set_dfuse_alt_setting(plan.alt_setting).await?;
+        for page in &plan.erases {
+            if self.dfuse_pages(page.address, 1)? != [*page] {
+                return Err(Error::PlanMismatch {
+                    address: page.address,
+                });
+            }
+        }
+        for segment in plan.firmware.segments() {
+            for page in self.dfuse_pages(segment.address, segment.data.len())? {
+                if !plan.erases.contains(&page) {
+                    return Err(Error::PlanMismatch {
+                        address: page.address.max(segment.address),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
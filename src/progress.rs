@@ -0,0 +1,205 @@
+//! Progress reporting for downloads and uploads.
+//!
+//! Progress is derived from the DFU requests [`DfuCrossUsb`](crate::DfuCrossUsb) sends, so it
+//! is reported for every download and upload path, whether it is driven by
+//! [`DfuSync`](crate::DfuSync), [`DfuAsync`](crate::DfuAsync) or the raw
+//! [`DfuIo`](dfu_core::DfuIo) requests.
+
+use crate::request::{DFUSE_ERASE, DFUSE_FIRST_BLOCK};
+use crate::{DFU_DNLOAD, DFU_GETSTATE, DFU_GETSTATUS, DFU_UPLOAD, runtime};
+use dfu_core::State;
+use std::time::Duration;
+
+/// The phase of a download or upload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// A DfuSe page or mass erase.
+    Erase,
+    /// Firmware blocks are written to the device.
+    Write,
+    /// The device manifests the downloaded firmware.
+    Manifest,
+    /// Firmware blocks are read from the device.
+    Upload,
+}
+
+/// A progress event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// The current phase.
+    pub phase: Phase,
+    /// The wValue of the last DFU_DNLOAD or DFU_UPLOAD request.
+    pub block_number: u16,
+    /// The number of firmware bytes written or read since the transfer started, DfuSe commands
+    /// excluded.
+    pub bytes: usize,
+    /// The number of firmware bytes of the transfer, if known. Downloads and read-back
+    /// verifications by [`DfuCrossUsb::download`](crate::DfuCrossUsb::download) and its
+    /// multi-image counterparts count up to it across all images.
+    pub total: Option<usize>,
+    /// The state of the device, as last reported by DFU_GETSTATUS or implied by the last
+    /// request.
+    pub state: Option<State>,
+}
+
+#[cfg(not(target_family = "wasm"))]
+type Callback = Box<dyn FnMut(Progress) + Send>;
+#[cfg(target_family = "wasm")]
+type Callback = Box<dyn FnMut(Progress)>;
+
+/// Follows the DFU requests of a device and reports progress to a callback.
+pub(crate) struct ProgressTracker {
+    callback: Option<Callback>,
+    dfuse: bool,
+    /// Whether a transfer of known size is in progress, which may span several downloads.
+    session: bool,
+    progress: Progress,
+    /// When the device started manifesting, as given by [`runtime::now`].
+    manifest_start: Option<Duration>,
+}
+
+impl ProgressTracker {
+    pub(crate) fn new(dfuse: bool) -> Self {
+        Self {
+            callback: None,
+            dfuse,
+            session: false,
+            progress: Progress {
+                phase: Phase::Write,
+                block_number: 0,
+                bytes: 0,
+                total: None,
+                state: None,
+            },
+            manifest_start: None,
+        }
+    }
+
+    pub(crate) fn set_callback(&mut self, callback: Callback) {
+        self.callback = Some(callback);
+    }
+
+    pub(crate) fn set_dfuse(&mut self, dfuse: bool) {
+        self.dfuse = dfuse;
+    }
+
+    /// Start a transfer of `total` bytes. Until [`end`](Self::end) the byte count only restarts
+    /// when the transfer switches between writing and reading back.
+    pub(crate) fn begin(&mut self, total: usize) {
+        self.session = true;
+        self.progress.bytes = 0;
+        self.progress.total = Some(total);
+    }
+
+    pub(crate) fn end(&mut self) {
+        self.session = false;
+        self.progress.total = None;
+    }
+
+    /// Whether the device was last seen manifesting the firmware.
+    pub(crate) fn is_manifesting(&self) -> bool {
+        matches!(
+            self.progress.state,
+            Some(State::DfuManifestSync | State::DfuManifest)
+        )
+    }
+
+    /// How long the device has been manifesting, `None` if it is not.
+    pub(crate) fn manifesting_for(&self) -> Option<Duration> {
+        let start = self.manifest_start.filter(|_| self.is_manifesting())?;
+        Some(runtime::now().saturating_sub(start))
+    }
+
+    /// Record a successful control OUT request.
+    pub(crate) fn control_out(&mut self, request: u8, value: u16, data: &[u8]) {
+        if request != DFU_DNLOAD {
+            return;
+        }
+        // DfuSe sends its commands as block 0, firmware starts at block 2.
+        let command = self.dfuse && value == 0;
+        if !command {
+            self.start_transfer(Phase::Write);
+        }
+
+        let progress = &mut self.progress;
+        progress.block_number = value;
+        if data.is_empty() {
+            progress.phase = Phase::Manifest;
+            progress.state = Some(State::DfuManifestSync);
+            self.manifest_start = Some(runtime::now());
+        } else {
+            match (command, data[0]) {
+                (true, DFUSE_ERASE) => progress.phase = Phase::Erase,
+                // Setting the address pointer belongs to the phase it prepares.
+                (true, _) => {}
+                (false, _) => {
+                    progress.phase = Phase::Write;
+                    progress.bytes += data.len();
+                }
+            }
+            progress.state = Some(State::DfuDnloadSync);
+        }
+        self.report();
+    }
+
+    /// Record a successful control IN request of `length` requested bytes.
+    pub(crate) fn control_in(&mut self, request: u8, value: u16, length: u16, data: &[u8]) {
+        match request {
+            DFU_UPLOAD => {
+                self.start_transfer(Phase::Upload);
+                let progress = &mut self.progress;
+                progress.phase = Phase::Upload;
+                progress.block_number = value;
+                if !self.dfuse || value >= DFUSE_FIRST_BLOCK {
+                    progress.bytes += data.len();
+                }
+                // A short frame ends the upload.
+                progress.state = Some(if data.len() < length as usize {
+                    State::DfuIdle
+                } else {
+                    State::DfuUploadIdle
+                });
+                self.report();
+            }
+            DFU_GETSTATUS if data.len() >= 6 => self.update_state(State::from(data[4])),
+            DFU_GETSTATE if !data.is_empty() => self.update_state(State::from(data[0])),
+            _ => {}
+        }
+    }
+
+    /// Whether a DFU_DNLOAD or DFU_UPLOAD sent now starts a new transfer: the device was last
+    /// seen in dfuIDLE and no [`begin`](Self::begin) session is in progress.
+    pub(crate) fn starts_transfer(&self) -> bool {
+        !self.session && matches!(self.progress.state, None | Some(State::DfuIdle))
+    }
+
+    /// Restart the byte count when a download or upload starts from dfuIDLE, or during a
+    /// session when switching between writing and reading back.
+    fn start_transfer(&mut self, phase: Phase) {
+        let restart = if self.session {
+            (self.progress.phase == Phase::Upload) != (phase == Phase::Upload)
+        } else {
+            matches!(self.progress.state, None | Some(State::DfuIdle))
+        };
+        if restart {
+            self.progress.bytes = 0;
+        }
+    }
+
+    fn update_state(&mut self, state: State) {
+        let manifesting = matches!(state, State::DfuManifestSync | State::DfuManifest);
+        if manifesting && self.manifesting_for().is_none() {
+            self.manifest_start = Some(runtime::now());
+        }
+        if self.progress.state != Some(state) {
+            self.progress.state = Some(state);
+            self.report();
+        }
+    }
+
+    fn report(&mut self) {
+        if let Some(callback) = self.callback.as_mut() {
+            callback(self.progress);
+        }
+    }
+}
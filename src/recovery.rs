@@ -0,0 +1,88 @@
+//! Bringing a device left in an unexpected state by a previous session back to dfuIDLE.
+
+use crate::{DfuCrossUsb, Error, UsbTransport, runtime};
+use dfu_core::{State, Status};
+use std::time::Duration;
+
+/// The number of requests [`DfuCrossUsb::prepare`] sends before it gives up.
+const MAX_STEPS: usize = 8;
+
+/// A request [`DfuCrossUsb::prepare`] sent to bring the device to dfuIDLE.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryAction {
+    /// DFU_CLRSTATUS, leaving dfuERROR.
+    ClearStatus,
+    /// DFU_ABORT, ending an unfinished download or upload.
+    Abort,
+}
+
+/// What [`DfuCrossUsb::prepare`] found and did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recovery {
+    /// The state the device was found in.
+    pub state: State,
+    /// The status the device reported, e.g. the error a previous session failed with.
+    pub status: Status,
+    /// The requests sent to bring the device to dfuIDLE, in order.
+    pub actions: Vec<RecoveryAction>,
+}
+
+impl Recovery {
+    /// Whether the device had to be recovered.
+    pub fn recovered(&self) -> bool {
+        !self.actions.is_empty()
+    }
+}
+
+impl<T: UsbTransport + 'static> DfuCrossUsb<T> {
+    /// Bring a device left in dfuERROR or in the middle of a download or upload back to
+    /// dfuIDLE, reporting what was found and done.
+    ///
+    /// The device is cleared with DFU_CLRSTATUS in dfuERROR and aborted with DFU_ABORT in
+    /// dfuDNLOAD-IDLE and dfuUPLOAD-IDLE; a device that is still busy is polled until it is
+    /// done. Devices in run-time mode or waiting for a reset after manifestation cannot be
+    /// recovered and fail with [`dfu_core::Error::InvalidState`].
+    pub async fn prepare(&self) -> Result<Recovery, Error> {
+        // The error of a block is recovered from, rather than reported.
+        crate::lock(&self.last_block).take();
+        let mut status = self.get_status().await?;
+        let mut recovery = Recovery {
+            state: status.state,
+            status: status.status,
+            actions: Vec::new(),
+        };
+        for _ in 0..MAX_STEPS {
+            match status.state {
+                State::DfuIdle => return Ok(recovery),
+                State::DfuError => {
+                    self.clear_status().await?;
+                    recovery.actions.push(RecoveryAction::ClearStatus);
+                }
+                State::DfuDnloadIdle | State::DfuUploadIdle => {
+                    self.abort().await?;
+                    recovery.actions.push(RecoveryAction::Abort);
+                }
+                // DFU_GETSTATUS moves the device on once bwPollTimeout elapsed.
+                State::DfuDnloadSync
+                | State::DfuDnbusy
+                | State::DfuManifestSync
+                | State::DfuManifest => {
+                    runtime::sleep(Duration::from_millis(status.poll_timeout as u64)).await?;
+                }
+                got => {
+                    return Err(dfu_core::Error::InvalidState {
+                        got,
+                        expected: State::DfuIdle,
+                    }
+                    .into());
+                }
+            }
+            status = self.get_status().await?;
+        }
+        Err(dfu_core::Error::InvalidState {
+            got: status.state,
+            expected: State::DfuIdle,
+        }
+        .into())
+    }
+}
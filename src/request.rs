@@ -0,0 +1,213 @@
+//! DFU class requests (DFU 1.1 Specification, Section 6) and the DfuSe commands sent through
+//! them (UM0391, Section 6).
+
+use crate::runtime;
+use crate::{
+    DFU_ABORT, DFU_CLRSTATUS, DFU_DETACH, DFU_DNLOAD, DFU_GETSTATE, DFU_GETSTATUS, DFU_REQUEST_IN,
+    DFU_REQUEST_OUT, DFU_UPLOAD, DeviceError, DfuCrossUsb, DfuseCommand, Error, UsbTransport,
+};
+use dfu_core::{State, Status};
+use std::fmt;
+use std::time::Duration;
+
+/// DfuSe command setting the address pointer.
+pub(crate) const DFUSE_SET_ADDRESS: u8 = 0x21;
+/// DfuSe command erasing a page, or the whole memory without an address.
+pub(crate) const DFUSE_ERASE: u8 = 0x41;
+/// DfuSe command removing the read protection.
+pub(crate) const DFUSE_READ_UNPROTECT: u8 = 0x92;
+
+/// The first DfuSe DFU_DNLOAD/DFU_UPLOAD block holding data, blocks 0 and 1 are reserved.
+pub(crate) const DFUSE_FIRST_BLOCK: u16 = 2;
+
+/// A DFU class request (DFU 1.1 Specification, Section 3), or one of the standard requests and
+/// the USB reset sent while opening and resetting the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DfuRequest {
+    Detach,
+    Dnload,
+    Upload,
+    GetStatus,
+    ClrStatus,
+    GetState,
+    Abort,
+    /// The standard SET_INTERFACE request selecting the alternate setting.
+    SetInterface,
+    /// A standard GET_DESCRIPTOR request.
+    GetDescriptor,
+    /// A USB reset.
+    UsbReset,
+    /// A bRequest that DFU does not define.
+    Other(u8),
+}
+
+impl From<u8> for DfuRequest {
+    fn from(request: u8) -> Self {
+        match request {
+            DFU_DETACH => Self::Detach,
+            DFU_DNLOAD => Self::Dnload,
+            DFU_UPLOAD => Self::Upload,
+            DFU_GETSTATUS => Self::GetStatus,
+            DFU_CLRSTATUS => Self::ClrStatus,
+            DFU_GETSTATE => Self::GetState,
+            DFU_ABORT => Self::Abort,
+            other => Self::Other(other),
+        }
+    }
+}
+
+impl fmt::Display for DfuRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Detach => f.write_str("DFU_DETACH"),
+            Self::Dnload => f.write_str("DFU_DNLOAD"),
+            Self::Upload => f.write_str("DFU_UPLOAD"),
+            Self::GetStatus => f.write_str("DFU_GETSTATUS"),
+            Self::ClrStatus => f.write_str("DFU_CLRSTATUS"),
+            Self::GetState => f.write_str("DFU_GETSTATE"),
+            Self::Abort => f.write_str("DFU_ABORT"),
+            Self::SetInterface => f.write_str("SET_INTERFACE"),
+            Self::GetDescriptor => f.write_str("GET_DESCRIPTOR"),
+            Self::UsbReset => f.write_str("USB reset"),
+            Self::Other(request) => write!(f, "request {request:#04x}"),
+        }
+    }
+}
+
+/// The payload of a DFU_GETSTATUS response.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct DeviceStatus {
+    pub status: Status,
+    pub poll_timeout: u32,
+    pub state: State,
+}
+
+impl<T: UsbTransport + 'static> DfuCrossUsb<T> {
+    pub(crate) fn is_dfuse(&self) -> bool {
+        matches!(self.protocol, dfu_core::DfuProtocol::Dfuse { .. })
+    }
+
+    pub(crate) async fn get_status(&self) -> Result<DeviceStatus, Error> {
+        let mut buffer = [0; 6];
+        let n = self
+            .read_control(DFU_REQUEST_IN, DFU_GETSTATUS, 0, &mut buffer)
+            .await?;
+        if n < buffer.len() {
+            return Err(dfu_core::Error::ResponseTooShort {
+                got: n,
+                expected: buffer.len(),
+            }
+            .into());
+        }
+        Ok(DeviceStatus {
+            status: Status::from(buffer[0]),
+            poll_timeout: u32::from_le_bytes([buffer[1], buffer[2], buffer[3], 0]),
+            state: State::from(buffer[4]),
+        })
+    }
+
+    pub(crate) async fn abort(&self) -> Result<(), Error> {
+        self.write_control(DFU_REQUEST_OUT, DFU_ABORT, 0, &[])
+            .await?;
+        Ok(())
+    }
+
+    pub(crate) async fn clear_status(&self) -> Result<(), Error> {
+        self.write_control(DFU_REQUEST_OUT, DFU_CLRSTATUS, 0, &[])
+            .await?;
+        Ok(())
+    }
+
+    pub(crate) async fn dnload_block(&self, block_num: u16, data: &[u8]) -> Result<(), Error> {
+        self.write_control(DFU_REQUEST_OUT, DFU_DNLOAD, block_num, data)
+            .await?;
+        Ok(())
+    }
+
+    pub(crate) async fn upload_block(
+        &self,
+        block_num: u16,
+        length: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let mut buffer = vec![0; length];
+        let n = self
+            .read_control(DFU_REQUEST_IN, DFU_UPLOAD, block_num, &mut buffer)
+            .await?;
+        buffer.truncate(n);
+        Ok(buffer)
+    }
+
+    /// Check that the device is in dfuIDLE, ready for a download or upload.
+    pub(crate) async fn expect_idle(&self) -> Result<(), Error> {
+        let status = self.get_status().await?;
+        if status.state != State::DfuIdle {
+            return Err(dfu_core::Error::InvalidState {
+                got: status.state,
+                expected: State::DfuIdle,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Poll DFU_GETSTATUS, honouring bwPollTimeout, until the device left the transitional
+    /// states of a DFU_DNLOAD and check that it ended up in `expected`.
+    ///
+    /// An error the device reports, possibly after several polls, fails with
+    /// [`Error::Device`]. Manifestation fails with [`Error::Timeout`] once the poll timeouts add
+    /// up to more than the manifestation timeout.
+    pub(crate) async fn wait_for_state(&self, expected: State) -> Result<DeviceStatus, Error> {
+        let mut manifesting = Duration::ZERO;
+        loop {
+            let status = self.get_status().await?;
+            if status.status != Status::Ok || status.state == State::DfuError {
+                return Err(Error::Device(DeviceError {
+                    status: status.status,
+                    state: status.state,
+                    block: None,
+                    description: None,
+                }));
+            }
+            let poll_timeout = Duration::from_millis(status.poll_timeout as u64);
+            match status.state {
+                State::DfuManifestSync | State::DfuManifest if status.state != expected => {
+                    // Count at least a millisecond per poll, so that a device answering with a
+                    // zero bwPollTimeout does not poll forever.
+                    manifesting += poll_timeout.max(Duration::from_millis(1));
+                    if manifesting > self.timeouts.manifestation {
+                        return Err(Error::Timeout {
+                            request: DfuRequest::GetStatus,
+                        });
+                    }
+                    runtime::sleep(poll_timeout).await?;
+                }
+                State::DfuDnloadSync | State::DfuDnbusy if status.state != expected => {
+                    runtime::sleep(poll_timeout).await?;
+                }
+                state if state == expected => return Ok(status),
+                got => return Err(dfu_core::Error::InvalidState { got, expected }.into()),
+            }
+        }
+    }
+
+    /// Send a DfuSe command and wait until the device executed it.
+    pub(crate) async fn dfuse_command(&self, command: DfuseCommand) -> Result<(), Error> {
+        self.dnload_block(0, &command.payload()).await?;
+        self.wait_for_command(command).await
+    }
+
+    /// Wait until the device executed a DfuSe command it accepted.
+    pub(crate) async fn wait_for_command(&self, command: DfuseCommand) -> Result<(), Error> {
+        match self.wait_for_state(State::DfuDnloadIdle).await {
+            Err(Error::Device(source)) => Err(Error::DfuseCommand {
+                command,
+                // Commands are sent as block 0.
+                source: DeviceError {
+                    block: Some(0),
+                    ..source
+                },
+            }),
+            result => result.map(drop),
+        }
+    }
+}
@@ -0,0 +1,226 @@
+//! Platform glue for driving `cross_usb` futures.
+//!
+//! On WASM the `cross_usb` futures wrap JS promises, which are not `Send`, so they are driven by
+//! the browser event loop and their result is sent back over a oneshot channel. On native targets
+//! the `cross_usb` futures are backed by `nusb` and can be awaited directly.
+
+use crate::Error;
+#[cfg(any(target_family = "wasm", test))]
+use futures::channel::oneshot;
+use futures::future::{self, Either};
+use std::pin::pin;
+use std::time::Duration;
+#[cfg(not(target_family = "wasm"))]
+use std::time::Instant;
+#[cfg(target_family = "wasm")]
+use wasm_bindgen::{JsCast, JsValue};
+#[cfg(target_family = "wasm")]
+use wasm_bindgen_futures::spawn_local;
+
+/// Shared ownership of USB handles between a device and its in-flight transfers.
+#[cfg(target_family = "wasm")]
+pub(crate) type Shared<T> = std::rc::Rc<T>;
+
+/// Shared ownership of USB handles between a device and its in-flight transfers.
+#[cfg(not(target_family = "wasm"))]
+pub(crate) type Shared<T> = std::sync::Arc<T>;
+
+/// Run a USB future and return a `Send` future resolving to its output.
+///
+/// Dropping the returned future abandons the USB future. If the task running the USB future is
+/// torn down before it completes, e.g. with the page, the returned future resolves to
+/// [`Error::TransferAbandoned`].
+#[cfg(target_family = "wasm")]
+pub(crate) fn spawn<T: Send + 'static>(
+    future: impl Future<Output = Result<T, Error>> + 'static,
+) -> impl Future<Output = Result<T, Error>> + Send {
+    let (task, output) = bridge(future);
+    spawn_local(task);
+    output
+}
+
+/// Split a future into a task running it and a `Send` future resolving to its output, or to
+/// [`Error::TransferAbandoned`] if the task is dropped before it completes. The task stops early
+/// once the output is no longer awaited.
+#[cfg(any(target_family = "wasm", test))]
+fn bridge<T: Send + 'static>(
+    future: impl Future<Output = Result<T, Error>> + 'static,
+) -> (
+    impl Future<Output = ()> + 'static,
+    impl Future<Output = Result<T, Error>> + Send,
+) {
+    let (mut tx, rx) = oneshot::channel();
+    let task = async move {
+        let output = match future::select(pin!(future), tx.cancellation()).await {
+            Either::Left((output, _)) => output,
+            // Nobody waits for the output any more.
+            Either::Right(_) => return,
+        };
+        let _ = tx.send(output);
+    };
+    (task, async move {
+        rx.await.unwrap_or(Err(Error::TransferAbandoned))
+    })
+}
+
+/// Run a USB future and return a `Send` future resolving to its output.
+///
+/// Dropping the returned future drops the USB future, which cancels the transfer.
+#[cfg(not(target_family = "wasm"))]
+pub(crate) fn spawn<T>(
+    future: impl Future<Output = Result<T, Error>> + Send,
+) -> impl Future<Output = Result<T, Error>> + Send {
+    future
+}
+
+/// Wait for `duration` without blocking the browser event loop, using `setTimeout`.
+///
+/// Fails with [`Error::TimerUnavailable`] if `setTimeout` cannot be called.
+#[cfg(target_family = "wasm")]
+pub(crate) fn sleep(duration: Duration) -> impl Future<Output = Result<(), Error>> + Send {
+    let millis = duration.as_millis().min(i32::MAX as u128) as i32;
+    let timer = spawn(async move {
+        let promise = js_sys::Promise::new(&mut |resolve, reject| {
+            // `setTimeout` is available on both windows and workers.
+            let global = js_sys::global();
+            let started = js_sys::Reflect::get(&global, &JsValue::from_str("setTimeout"))
+                .and_then(|set_timeout| set_timeout.dyn_into::<js_sys::Function>())
+                .and_then(|set_timeout| {
+                    set_timeout.call2(&global, &resolve, &JsValue::from(millis))
+                });
+            if let Err(error) = started {
+                let _ = reject.call1(&JsValue::UNDEFINED, &error);
+            }
+        });
+        wasm_bindgen_futures::JsFuture::from(promise)
+            .await
+            .map(|_| ())
+            .map_err(|_| Error::TimerUnavailable)
+    });
+    async move {
+        match timer.await {
+            // A timer torn down with the page has nothing left to wait for.
+            Err(Error::TransferAbandoned) => Ok(()),
+            result => result,
+        }
+    }
+}
+
+/// Wait for `duration` on the timer thread, so that the executor is not blocked.
+#[cfg(not(target_family = "wasm"))]
+pub(crate) fn sleep(duration: Duration) -> impl Future<Output = Result<(), Error>> + Send {
+    let wake = (!duration.is_zero()).then(|| timer::wake_at(Instant::now() + duration));
+    async move {
+        if let Some(wake) = wake {
+            let _ = wake.await;
+        }
+        Ok(())
+    }
+}
+
+/// The time elapsed since a fixed point, to measure durations spanning several requests.
+#[cfg(target_family = "wasm")]
+pub(crate) fn now() -> Duration {
+    Duration::from_secs_f64(js_sys::Date::now() / 1000.0)
+}
+
+/// The time elapsed since a fixed point, to measure durations spanning several requests.
+#[cfg(not(target_family = "wasm"))]
+pub(crate) fn now() -> Duration {
+    static START: std::sync::OnceLock<Instant> = std::sync::OnceLock::new();
+    START.get_or_init(Instant::now).elapsed()
+}
+
+/// Run `future` for at most `duration`, `None` if it did not complete in time.
+pub(crate) async fn timeout<T>(
+    duration: Duration,
+    future: impl Future<Output = T>,
+) -> Result<Option<T>, Error> {
+    match future::select(pin!(future), pin!(sleep(duration))).await {
+        Either::Left((output, _)) => Ok(Some(output)),
+        Either::Right((timer, _)) => timer.map(|()| None),
+    }
+}
+
+/// A single thread waking the sleeps and timeouts of all devices, rather than a thread per
+/// control transfer.
+#[cfg(not(target_family = "wasm"))]
+mod timer {
+    use futures::channel::oneshot;
+    use std::sync::OnceLock;
+    use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+    use std::time::Instant;
+
+    type Timer = (Instant, oneshot::Sender<()>);
+
+    static TIMER: OnceLock<Sender<Timer>> = OnceLock::new();
+
+    /// A receiver completing at `deadline`.
+    pub(super) fn wake_at(deadline: Instant) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        let timer = TIMER.get_or_init(|| {
+            let (timers, receiver) = mpsc::channel();
+            std::thread::Builder::new()
+                .name("dfu-cross-usb timer".into())
+                .spawn(move || run(receiver))
+                .expect("failed to spawn the timer thread");
+            timers
+        });
+        // The thread lives as long as the process, so the send cannot fail.
+        let _ = timer.send((deadline, tx));
+        rx
+    }
+
+    fn run(receiver: Receiver<Timer>) {
+        let mut timers: Vec<Timer> = Vec::new();
+        loop {
+            let now = Instant::now();
+            let (expired, pending): (Vec<Timer>, Vec<Timer>) = timers
+                .into_iter()
+                .partition(|(deadline, _)| *deadline <= now);
+            for (_, wake) in expired {
+                let _ = wake.send(());
+            }
+            timers = pending;
+            // Timeouts of transfers that completed are no longer awaited.
+            timers.retain(|(_, wake)| !wake.is_canceled());
+
+            let timer = match timers.iter().map(|(deadline, _)| *deadline).min() {
+                Some(next) => receiver.recv_timeout(next.saturating_duration_since(now)),
+                None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match timer {
+                Ok(timer) => timers.push(timer),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn bridge_resolves_to_output_of_task() {
+        let (task, output) = bridge(async { Ok(7) });
+        block_on(task);
+        assert_eq!(block_on(output).unwrap(), 7);
+    }
+
+    #[test]
+    fn bridge_is_abandoned_when_task_is_torn_down() {
+        let (task, output) = bridge(future::pending::<Result<(), Error>>());
+        drop(task);
+        assert!(matches!(block_on(output), Err(Error::TransferAbandoned)));
+    }
+
+    #[test]
+    fn bridge_task_stops_once_output_is_dropped() {
+        let (task, output) = bridge(future::pending::<Result<(), Error>>());
+        drop(output);
+        block_on(task);
+    }
+}
@@ -0,0 +1,827 @@
+//! In-memory simulated DFU 1.1 device.
+//!
+//! [`SimulatedDevice`] implements [`UsbTransport`] and the DFU 1.1 state machine on top of a
+//! backing flash buffer, so that downloads, uploads and error paths can be exercised without
+//! hardware:
+//!
+//! ```
+//! # futures::executor::block_on(async {
+//! use dfu_cross_usb::{DfuCrossUsb, sim::SimulatedDevice};
+//!
+//! let device = SimulatedDevice::new(1024).with_transfer_size(64);
+//! let mut dfu = DfuCrossUsb::from_transport(device, 0, 0).await?.into_async_dfu();
+//! dfu.download_from_slice(&[0xaa; 100]).await?;
+//!
+//! let device = dfu.into_inner();
+//! assert_eq!(&device.transport().flash()[..100], &[0xaa; 100]);
+//! # Ok::<(), dfu_cross_usb::Error>(())
+//! # }).unwrap();
+//! ```
+//!
+//! Requests that are not valid in the current state are stalled and move the device to
+//! dfuERROR with errSTALLEDPKT, like a real device would.
+//!
+//! With a bcdDFUVersion of `0x011a` the device speaks DfuSe: the flash is mapped at the address
+//! of the iInterface string of the selected alternate setting and is erased with its page
+//! layout, blocks are addressed through the address pointer and programming can only clear
+//! bits, like NOR flash. Alternate settings share the flash unless they are given their own
+//! memory with [`SimulatedDevice::with_memory`].
+
+use crate::descriptor::{DFU_MODE_PROTOCOL, DFU_SUBCLASS, RUNTIME_PROTOCOL};
+use crate::memory;
+use crate::request::{DFUSE_ERASE, DFUSE_FIRST_BLOCK, DFUSE_READ_UNPROTECT, DFUSE_SET_ADDRESS};
+use crate::{
+    DFU_ABORT, DFU_CLRSTATUS, DFU_DETACH, DFU_DNLOAD, DFU_FUNCTIONAL_DESCRIPTOR_TYPE, DFU_GETSTATE,
+    DFU_GETSTATUS, DFU_UPLOAD, DFUSE_VERSION, Error, UsbTransport,
+};
+use dfu_core::{DfuProtocol, State, Status};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use usb::{descriptor_type, language_id, request_type, standard_request};
+
+/// bmAttributes bit 0: bitCanDnload.
+pub const CAN_DOWNLOAD: u8 = 1 << 0;
+/// bmAttributes bit 1: bitCanUpload.
+pub const CAN_UPLOAD: u8 = 1 << 1;
+/// bmAttributes bit 2: bitManifestationTolerant.
+pub const MANIFESTATION_TOLERANT: u8 = 1 << 2;
+/// bmAttributes bit 3: bitWillDetach.
+pub const WILL_DETACH: u8 = 1 << 3;
+
+const REQUEST_TYPE_MASK: u8 = request_type::REQUEST_TYPE_MASK | request_type::RECIPIENT_MASK;
+const STANDARD_DEVICE: u8 = request_type::request_type::STANDARD | request_type::recipient::DEVICE;
+const STANDARD_INTERFACE: u8 =
+    request_type::request_type::STANDARD | request_type::recipient::INTERFACE;
+const CLASS_INTERFACE: u8 = request_type::request_type::CLASS | request_type::recipient::INTERFACE;
+
+/// DfuSe command reading the supported commands.
+const DFUSE_GET_COMMANDS: u8 = 0x00;
+
+/// A simulated DFU 1.1 device with a single DFU interface.
+///
+/// Clones share the state of the device, so a test can keep a handle on a device that was moved
+/// into a [`DfuCrossUsb`](crate::DfuCrossUsb).
+#[derive(Clone)]
+pub struct SimulatedDevice {
+    config: Config,
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Clone)]
+struct Config {
+    vendor_id: u16,
+    product_id: u16,
+    bcd_device: u16,
+    serial_number: String,
+    attributes: u8,
+    detach_timeout: u16,
+    transfer_size: u16,
+    dfu_version: u16,
+    poll_timeout: u32,
+    alt_settings: Vec<String>,
+    /// The string describing errors, at the index following the iInterface strings.
+    error_string: Option<String>,
+}
+
+enum Operation {
+    /// Program `data` at flash offset `offset`, `None` if the block is outside the flash.
+    Write {
+        block_num: u16,
+        offset: Option<usize>,
+        data: Vec<u8>,
+    },
+    SetAddress(u32),
+    /// Erase the page containing an address, or the whole flash.
+    Erase(Option<u32>),
+    /// Erase the whole flash and remove the read protection.
+    ReadUnprotect,
+}
+
+struct Inner {
+    state: State,
+    status: Status,
+    flash: Vec<u8>,
+    /// Memories of alternate settings that do not share the flash.
+    memories: HashMap<u8, Vec<u8>>,
+    alt_setting: u8,
+    /// Byte offset of the next DNLOAD or UPLOAD block.
+    offset: usize,
+    /// DfuSe address pointer.
+    address: u32,
+    /// Operation received with DNLOAD, executed when the host asks for the status.
+    pending: Option<Operation>,
+    /// Remaining GETSTATUS requests answered with dfuDNBUSY before a block is written.
+    busy_polls: u32,
+    busy_polls_left: u32,
+    /// Remaining GETSTATUS requests answered with dfuMANIFEST before manifestation completes.
+    manifestation_polls: u32,
+    /// Flash range that silently ignores writes and erases.
+    write_protected: Range<usize>,
+    /// Whether DFU_UPLOAD of the memory is refused.
+    read_protected: bool,
+    /// Block whose next programming fails, and the status it fails with.
+    fail_block: Option<(u16, Status)>,
+    /// bmRequestType type and recipient and bRequest of a request that is never answered.
+    hang: Option<(u8, u8)>,
+    hang_on_reset: bool,
+    resets: usize,
+}
+
+impl Inner {
+    /// The memory of the selected alternate setting.
+    fn memory(&mut self) -> &mut Vec<u8> {
+        match self.memories.get_mut(&self.alt_setting) {
+            Some(memory) => memory,
+            None => &mut self.flash,
+        }
+    }
+}
+
+impl SimulatedDevice {
+    /// Create a DFU 1.1 device in dfuIDLE with `flash_size` bytes of erased (0xff) flash.
+    ///
+    /// The device can download and upload and is manifestation tolerant.
+    pub fn new(flash_size: usize) -> Self {
+        Self {
+            config: Config {
+                vendor_id: 0x1209,
+                product_id: 0xdf11,
+                bcd_device: 0x0100,
+                serial_number: "SIM0001".into(),
+                attributes: CAN_DOWNLOAD | CAN_UPLOAD | MANIFESTATION_TOLERANT,
+                detach_timeout: 1000,
+                transfer_size: 1024,
+                dfu_version: 0x0110,
+                poll_timeout: 0,
+                alt_settings: vec!["Simulated Flash".into()],
+                error_string: None,
+            },
+            inner: Arc::new(Mutex::new(Inner {
+                state: State::DfuIdle,
+                status: Status::Ok,
+                flash: vec![0xff; flash_size],
+                memories: HashMap::new(),
+                alt_setting: 0,
+                offset: 0,
+                address: 0,
+                pending: None,
+                busy_polls: 0,
+                busy_polls_left: 0,
+                manifestation_polls: 0,
+                write_protected: 0..0,
+                read_protected: false,
+                fail_block: None,
+                hang: None,
+                hang_on_reset: false,
+                resets: 0,
+            })),
+        }
+    }
+
+    /// Set the idVendor, idProduct and bcdDevice of the device descriptor.
+    pub fn with_ids(mut self, vendor_id: u16, product_id: u16, bcd_device: u16) -> Self {
+        self.config.vendor_id = vendor_id;
+        self.config.product_id = product_id;
+        self.config.bcd_device = bcd_device;
+        self
+    }
+
+    /// Set the iSerialNumber string of the device descriptor.
+    pub fn with_serial_number(mut self, serial_number: impl Into<String>) -> Self {
+        self.config.serial_number = serial_number.into();
+        self
+    }
+
+    /// Set the bmAttributes of the functional descriptor, see [`CAN_DOWNLOAD`] and friends.
+    pub fn with_attributes(mut self, attributes: u8) -> Self {
+        self.config.attributes = attributes;
+        self
+    }
+
+    /// Set the wDetachTimeOut of the functional descriptor, in milliseconds.
+    pub fn with_detach_timeout(mut self, detach_timeout: u16) -> Self {
+        self.config.detach_timeout = detach_timeout;
+        self
+    }
+
+    /// Set the wTransferSize of the functional descriptor.
+    pub fn with_transfer_size(mut self, transfer_size: u16) -> Self {
+        self.config.transfer_size = transfer_size;
+        self
+    }
+
+    /// Set the bcdDFUVersion of the functional descriptor, e.g. `0x011a` for DfuSe.
+    pub fn with_dfu_version(mut self, dfu_version: u16) -> Self {
+        self.config.dfu_version = dfu_version;
+        self
+    }
+
+    /// Set the iInterface strings of the alternate settings of the DFU interface.
+    pub fn with_alt_settings<S: Into<String>>(
+        mut self,
+        alt_settings: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.config.alt_settings = alt_settings.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Describe errors with a string descriptor, whose index DFU_GETSTATUS reports in iString
+    /// while the status is not OK.
+    pub fn with_error_string(mut self, error_string: impl Into<String>) -> Self {
+        self.config.error_string = Some(error_string.into());
+        self
+    }
+
+    /// Set the bwPollTimeout reported by DFU_GETSTATUS, in milliseconds.
+    pub fn with_poll_timeout(mut self, poll_timeout: u32) -> Self {
+        self.config.poll_timeout = poll_timeout;
+        self
+    }
+
+    /// Answer this many DFU_GETSTATUS requests with dfuDNBUSY before a block is programmed.
+    pub fn with_busy_polls(self, busy_polls: u32) -> Self {
+        self.lock().busy_polls = busy_polls;
+        self
+    }
+
+    /// Answer this many DFU_GETSTATUS requests with dfuMANIFEST before manifestation completes.
+    pub fn with_manifestation_polls(self, manifestation_polls: u32) -> Self {
+        self.lock().manifestation_polls = manifestation_polls;
+        self
+    }
+
+    /// Start in run-time mode (appIDLE), the device enters DFU mode after a DFU_DETACH.
+    pub fn with_runtime_mode(self) -> Self {
+        self.lock().state = State::AppIdle;
+        self
+    }
+
+    /// Replace the contents of the flash.
+    pub fn with_flash(self, flash: Vec<u8>) -> Self {
+        self.lock().flash = flash;
+        self
+    }
+
+    /// Give alternate setting `alt_setting` its own memory rather than the flash, like the
+    /// option bytes or OTP area of a DfuSe device.
+    pub fn with_memory(self, alt_setting: u8, memory: Vec<u8>) -> Self {
+        self.lock().memories.insert(alt_setting, memory);
+        self
+    }
+
+    /// Put the device in `state` with `status`, e.g. to simulate a previous session that failed.
+    pub fn with_state(self, state: State, status: Status) -> Self {
+        {
+            let mut inner = self.lock();
+            inner.state = state;
+            inner.status = status;
+        }
+        self
+    }
+
+    /// Silently ignore writes and erases of a range of the flash, like a write protected
+    /// sector.
+    pub fn with_write_protection(self, range: Range<usize>) -> Self {
+        self.lock().write_protected = range;
+        self
+    }
+
+    /// Refuse DFU_UPLOAD of the memory until the read protection is removed with the DfuSe
+    /// read unprotect command, like an STM32 at read protection level 1.
+    pub fn with_read_protection(self) -> Self {
+        self.lock().read_protected = true;
+        self
+    }
+
+    /// Whether the memory is read protected.
+    pub fn is_read_protected(&self) -> bool {
+        self.lock().read_protected
+    }
+
+    /// Fail the next programming of DNLOAD block `block_num` with `status`, like a transient
+    /// flash error.
+    pub fn fail_block(&self, block_num: u16, status: Status) {
+        self.lock().fail_block = Some((block_num, status));
+    }
+
+    /// Never answer DFU class request `request`, like a stuck bootloader.
+    pub fn hang_on(&self, request: u8) {
+        self.lock().hang = Some((CLASS_INTERFACE, request));
+    }
+
+    /// Never answer standard interface request `request`, e.g. SET_INTERFACE.
+    pub fn hang_on_standard(&self, request: u8) {
+        self.lock().hang = Some((STANDARD_INTERFACE, request));
+    }
+
+    /// Never complete a USB reset.
+    pub fn hang_on_reset(&self) {
+        self.lock().hang_on_reset = true;
+    }
+
+    /// Contents of the flash.
+    pub fn flash(&self) -> Vec<u8> {
+        self.lock().flash.clone()
+    }
+
+    /// Contents of the memory of alternate setting `alt_setting`, the flash unless it was given
+    /// its own with [`with_memory`](Self::with_memory).
+    pub fn memory(&self, alt_setting: u8) -> Vec<u8> {
+        let inner = self.lock();
+        inner
+            .memories
+            .get(&alt_setting)
+            .unwrap_or(&inner.flash)
+            .clone()
+    }
+
+    /// Current DFU state.
+    pub fn state(&self) -> State {
+        self.lock().state
+    }
+
+    /// Current DFU status.
+    pub fn status(&self) -> Status {
+        self.lock().status
+    }
+
+    /// Selected alternate setting.
+    pub fn alt_setting(&self) -> u8 {
+        self.lock().alt_setting
+    }
+
+    /// DfuSe address pointer, the address the firmware starts from once the device left DFU
+    /// mode.
+    pub fn address(&self) -> u32 {
+        self.lock().address
+    }
+
+    /// Number of USB resets the device received.
+    pub fn resets(&self) -> usize {
+        self.lock().resets
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn functional_descriptor(&self) -> [u8; 9] {
+        let [detach_lo, detach_hi] = self.config.detach_timeout.to_le_bytes();
+        let [transfer_lo, transfer_hi] = self.config.transfer_size.to_le_bytes();
+        let [version_lo, version_hi] = self.config.dfu_version.to_le_bytes();
+        [
+            9,
+            DFU_FUNCTIONAL_DESCRIPTOR_TYPE,
+            self.config.attributes,
+            detach_lo,
+            detach_hi,
+            transfer_lo,
+            transfer_hi,
+            version_lo,
+            version_hi,
+        ]
+    }
+
+    fn device_descriptor(&self) -> Vec<u8> {
+        let mut descriptor = vec![18, descriptor_type::DEVICE, 0x00, 0x02, 0, 0, 0, 64];
+        descriptor.extend_from_slice(&self.config.vendor_id.to_le_bytes());
+        descriptor.extend_from_slice(&self.config.product_id.to_le_bytes());
+        descriptor.extend_from_slice(&self.config.bcd_device.to_le_bytes());
+        descriptor.extend_from_slice(&[1, 2, 3, 1]);
+        descriptor
+    }
+
+    fn configuration_descriptor(&self, inner: &Inner) -> Vec<u8> {
+        let protocol = if Self::in_runtime_mode(inner) {
+            RUNTIME_PROTOCOL
+        } else {
+            DFU_MODE_PROTOCOL
+        };
+        let mut descriptor = vec![9, descriptor_type::CONFIGURATION, 0, 0, 1, 1, 0, 0x80, 50];
+        for alt_setting in 0..self.config.alt_settings.len() as u8 {
+            descriptor.extend_from_slice(&[
+                9,
+                descriptor_type::INTERFACE,
+                0,
+                alt_setting,
+                0,
+                usb::class_code::APPLICATION,
+                DFU_SUBCLASS,
+                protocol,
+                4 + alt_setting,
+            ]);
+        }
+        // Like most bootloaders, the functional descriptor follows the last alternate setting.
+        descriptor.extend_from_slice(&self.functional_descriptor());
+        let total_length = (descriptor.len() as u16).to_le_bytes();
+        descriptor[2..4].copy_from_slice(&total_length);
+        descriptor
+    }
+
+    fn string_descriptor(&self, index: u8) -> Option<Vec<u8>> {
+        let string = match index {
+            0 => return Some(vec![4, descriptor_type::STRING, 0x09, 0x04]),
+            1 => "dfu-cross-usb",
+            2 => "Simulated DFU device",
+            3 => &self.config.serial_number,
+            i if i == self.error_string_index() => self.config.error_string.as_deref()?,
+            i => self.config.alt_settings.get(i as usize - 4)?,
+        };
+        let mut descriptor = vec![0, descriptor_type::STRING];
+        for c in string.encode_utf16() {
+            descriptor.extend_from_slice(&c.to_le_bytes());
+        }
+        descriptor[0] = descriptor.len() as u8;
+        Some(descriptor)
+    }
+
+    fn error_string_index(&self) -> u8 {
+        4 + self.config.alt_settings.len() as u8
+    }
+
+    fn get_descriptor(&self, inner: &Inner, value: u16, index: u16) -> Option<Vec<u8>> {
+        let [descriptor_index, descriptor_type] = value.to_le_bytes();
+        match descriptor_type {
+            descriptor_type::DEVICE => Some(self.device_descriptor()),
+            descriptor_type::CONFIGURATION if descriptor_index == 0 => {
+                Some(self.configuration_descriptor(inner))
+            }
+            descriptor_type::STRING if index == 0 || index == language_id::ENGLISH_US => {
+                self.string_descriptor(descriptor_index)
+            }
+            _ => None,
+        }
+    }
+
+    fn get_status(&self, inner: &mut Inner) -> Vec<u8> {
+        match inner.state {
+            State::DfuDnloadSync | State::DfuDnbusy if inner.busy_polls_left > 0 => {
+                inner.busy_polls_left -= 1;
+                inner.state = State::DfuDnbusy;
+            }
+            State::DfuDnloadSync | State::DfuDnbusy => {
+                inner.state = State::DfuDnloadIdle;
+                if let Some(operation) = inner.pending.take()
+                    && let Err(status) = self.execute(inner, operation)
+                {
+                    inner.state = State::DfuError;
+                    inner.status = status;
+                }
+            }
+            State::DfuManifestSync if inner.manifestation_polls > 0 => {
+                inner.manifestation_polls -= 1;
+                return self.status_bytes(inner.status, State::DfuManifest);
+            }
+            State::DfuManifestSync => {
+                inner.state = if self.config.attributes & MANIFESTATION_TOLERANT != 0 {
+                    State::DfuIdle
+                } else {
+                    State::DfuManifestWaitReset
+                };
+                // The device reports the state it is in while it manifests the firmware.
+                return self.status_bytes(inner.status, State::DfuManifest);
+            }
+            _ => {}
+        }
+        self.status_bytes(inner.status, inner.state)
+    }
+
+    fn status_bytes(&self, status: Status, state: State) -> Vec<u8> {
+        let [timeout_0, timeout_1, timeout_2, _] = self.config.poll_timeout.to_le_bytes();
+        vec![
+            status.into(),
+            timeout_0,
+            timeout_1,
+            timeout_2,
+            state.into(),
+            match (status, &self.config.error_string) {
+                (Status::Ok, _) | (_, None) => 0,
+                _ => self.error_string_index(),
+            },
+        ]
+    }
+
+    fn execute(&self, inner: &mut Inner, operation: Operation) -> Result<(), Status> {
+        match operation {
+            Operation::Write {
+                block_num,
+                offset,
+                data,
+            } => {
+                if let Some((_, status)) = inner
+                    .fail_block
+                    .take_if(|(fail_block, _)| *fail_block == block_num)
+                {
+                    return Err(status);
+                }
+                let offset = offset.ok_or(Status::ErrAddress)?;
+                let end = offset + data.len();
+                if end > inner.memory().len() {
+                    return Err(Status::ErrAddress);
+                }
+                let flash = self.is_flash(inner, offset);
+                Self::program(inner, offset..end, |i, byte| {
+                    let new = data[i - offset];
+                    if flash { *byte & new } else { new }
+                });
+                inner.offset = end;
+            }
+            Operation::SetAddress(address) => inner.address = address,
+            Operation::Erase(None) => {
+                let len = inner.memory().len();
+                Self::program(inner, 0..len, |_, _| 0xff);
+            }
+            Operation::ReadUnprotect => {
+                let len = inner.memory().len();
+                Self::program(inner, 0..len, |_, _| 0xff);
+                inner.read_protected = false;
+            }
+            Operation::Erase(Some(address)) => {
+                let (start, end) = self.page(inner, address).ok_or(Status::ErrAddress)?;
+                let end = end.min(inner.memory().len());
+                Self::program(inner, start.min(end)..end, |_, _| 0xff);
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the bytes of a memory range outside the write protected range.
+    fn program(inner: &mut Inner, range: Range<usize>, value: impl Fn(usize, &u8) -> u8) {
+        let write_protected = inner.write_protected.clone();
+        let memory = inner.memory();
+        for i in range {
+            if !write_protected.contains(&i) {
+                memory[i] = value(i, &memory[i]);
+            }
+        }
+    }
+
+    fn is_dfuse(&self) -> bool {
+        self.config.dfu_version.to_be_bytes() == [DFUSE_VERSION.0, DFUSE_VERSION.1]
+    }
+
+    /// Whether the DfuSe page at flash offset `offset` is erasable flash, whose bits
+    /// programming can only clear, rather than memory the device erases itself while writing
+    /// it, like option bytes.
+    fn is_flash(&self, inner: &Inner, offset: usize) -> bool {
+        let Some(interface_string) = self.config.alt_settings.get(inner.alt_setting as usize)
+        else {
+            return false;
+        };
+        let Ok(protocol) = DfuProtocol::new(interface_string, DFUSE_VERSION) else {
+            return false;
+        };
+        let pages = memory::pages(interface_string, &protocol);
+        let address = pages.first().map_or(0, |page| page.address as u64) + offset as u64;
+        self.is_dfuse()
+            && pages
+                .iter()
+                .find(|page| page.contains(address))
+                .is_some_and(|page| page.is_erasable())
+    }
+
+    /// The start address and page sizes of the memory of the selected alternate setting.
+    fn region(&self, inner: &Inner) -> Option<(u32, Vec<u32>)> {
+        let interface_string = self.config.alt_settings.get(inner.alt_setting as usize)?;
+        match DfuProtocol::new(interface_string, DFUSE_VERSION).ok()? {
+            DfuProtocol::Dfuse {
+                address,
+                memory_layout,
+            } => Some((address, memory_layout.as_slice().to_vec())),
+            DfuProtocol::Dfu => None,
+        }
+    }
+
+    /// The flash offset of a DfuSe address.
+    fn flash_offset(&self, inner: &Inner, address: u32) -> Option<usize> {
+        let (start, _) = self.region(inner)?;
+        Some(address.checked_sub(start)? as usize)
+    }
+
+    /// The flash offsets of the page containing a DfuSe address.
+    fn page(&self, inner: &Inner, address: u32) -> Option<(usize, usize)> {
+        let (start, pages) = self.region(inner)?;
+        let offset = address.checked_sub(start)? as usize;
+        let mut page_start = 0;
+        for page_size in pages {
+            let page_end = page_start + page_size as usize;
+            if offset < page_end {
+                return Some((page_start, page_end));
+            }
+            page_start = page_end;
+        }
+        None
+    }
+
+    /// The flash offset of DfuSe block `block_num`, relative to the address pointer.
+    fn block_offset(&self, inner: &Inner, block_num: u16) -> Option<usize> {
+        let block_address =
+            (block_num - DFUSE_FIRST_BLOCK) as u32 * self.config.transfer_size as u32;
+        self.flash_offset(inner, inner.address.checked_add(block_address)?)
+    }
+
+    fn upload(&self, inner: &mut Inner, block_num: u16, length: u16) -> Option<Vec<u8>> {
+        match inner.state {
+            State::DfuIdle => inner.offset = 0,
+            State::DfuUploadIdle => {}
+            _ => return None,
+        }
+        if self.is_dfuse() {
+            match block_num {
+                0 => {
+                    inner.state = State::DfuIdle;
+                    return Some(vec![
+                        DFUSE_GET_COMMANDS,
+                        DFUSE_SET_ADDRESS,
+                        DFUSE_ERASE,
+                        DFUSE_READ_UNPROTECT,
+                    ]);
+                }
+                1 => return None,
+                _ => inner.offset = self.block_offset(inner, block_num)?,
+            }
+        }
+        if inner.read_protected {
+            return None;
+        }
+        let offset = inner.offset;
+        let memory = inner.memory();
+        let end = (offset + length as usize).min(memory.len());
+        let data = memory[offset.min(end)..end].to_vec();
+        inner.offset = end;
+        // A short frame ends the upload.
+        inner.state = if data.len() < length as usize {
+            State::DfuIdle
+        } else {
+            State::DfuUploadIdle
+        };
+        Some(data)
+    }
+
+    fn download(&self, inner: &mut Inner, block_num: u16, data: &[u8]) -> Option<()> {
+        match inner.state {
+            State::DfuIdle if !data.is_empty() => inner.offset = 0,
+            State::DfuDnloadIdle if data.is_empty() => {
+                inner.state = State::DfuManifestSync;
+                return Some(());
+            }
+            State::DfuDnloadIdle => {}
+            _ => return None,
+        }
+        if data.len() > self.config.transfer_size as usize {
+            return None;
+        }
+        let operation = match block_num {
+            0 if self.is_dfuse() => match *data {
+                [DFUSE_SET_ADDRESS, a, b, c, d] => {
+                    Operation::SetAddress(u32::from_le_bytes([a, b, c, d]))
+                }
+                [DFUSE_ERASE] => Operation::Erase(None),
+                [DFUSE_READ_UNPROTECT] => Operation::ReadUnprotect,
+                [DFUSE_ERASE, a, b, c, d] => {
+                    Operation::Erase(Some(u32::from_le_bytes([a, b, c, d])))
+                }
+                _ => return None,
+            },
+            1 if self.is_dfuse() => return None,
+            _ if self.is_dfuse() => Operation::Write {
+                block_num,
+                offset: self.block_offset(inner, block_num),
+                data: data.to_vec(),
+            },
+            _ => Operation::Write {
+                block_num,
+                offset: Some(inner.offset),
+                data: data.to_vec(),
+            },
+        };
+        inner.pending = Some(operation);
+        inner.busy_polls_left = inner.busy_polls;
+        inner.state = State::DfuDnloadSync;
+        Some(())
+    }
+
+    fn detach(&self, inner: &mut Inner) -> Option<()> {
+        if inner.state != State::AppIdle {
+            return None;
+        }
+        // A device with bitWillDetach performs the detach-attach sequence itself, others wait
+        // for the host to reset them.
+        inner.state = if self.config.attributes & WILL_DETACH != 0 {
+            State::DfuIdle
+        } else {
+            State::AppDetach
+        };
+        Some(())
+    }
+
+    fn in_runtime_mode(inner: &Inner) -> bool {
+        matches!(inner.state, State::AppIdle | State::AppDetach)
+    }
+
+    fn stall(inner: &mut Inner) -> Error {
+        if !Self::in_runtime_mode(inner) {
+            inner.state = State::DfuError;
+            inner.status = Status::ErrStalledpkt;
+        }
+        cross_usb::usb::Error::TransferError.into()
+    }
+
+    /// Never complete a request set up with [`hang_on`](Self::hang_on) or
+    /// [`hang_on_standard`](Self::hang_on_standard).
+    async fn hang(&self, request_type: u8, request: u8) {
+        let hang = self.lock().hang;
+        if hang == Some((request_type & REQUEST_TYPE_MASK, request)) {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+impl UsbTransport for SimulatedDevice {
+    async fn control_in(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> Result<Vec<u8>, Error> {
+        self.hang(request_type, request).await;
+        let mut inner = self.lock();
+        let mut data = match (request_type & REQUEST_TYPE_MASK, request) {
+            (STANDARD_DEVICE, standard_request::GET_DESCRIPTOR) => {
+                self.get_descriptor(&inner, value, index)
+            }
+            (CLASS_INTERFACE, _) if inner.state == State::DfuManifestWaitReset => None,
+            (CLASS_INTERFACE, DFU_GETSTATUS) => Some(self.get_status(&mut inner)),
+            (CLASS_INTERFACE, DFU_GETSTATE) => Some(vec![inner.state.into()]),
+            (CLASS_INTERFACE, _) if Self::in_runtime_mode(&inner) => None,
+            (CLASS_INTERFACE, DFU_UPLOAD) if self.config.attributes & CAN_UPLOAD != 0 => {
+                self.upload(&mut inner, value, length)
+            }
+            _ => None,
+        }
+        .ok_or_else(|| Self::stall(&mut inner))?;
+        data.truncate(length as usize);
+        Ok(data)
+    }
+
+    async fn control_out(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        _index: u16,
+        data: &[u8],
+    ) -> Result<usize, Error> {
+        self.hang(request_type, request).await;
+        let mut inner = self.lock();
+        match (request_type & REQUEST_TYPE_MASK, request) {
+            (STANDARD_INTERFACE, standard_request::SET_INTERFACE)
+                if (value as usize) < self.config.alt_settings.len() =>
+            {
+                inner.alt_setting = value as u8;
+                Some(())
+            }
+            (CLASS_INTERFACE, _) if inner.state == State::DfuManifestWaitReset => None,
+            (CLASS_INTERFACE, DFU_DETACH) => self.detach(&mut inner),
+            (CLASS_INTERFACE, _) if Self::in_runtime_mode(&inner) => None,
+            (CLASS_INTERFACE, DFU_DNLOAD) if self.config.attributes & CAN_DOWNLOAD != 0 => {
+                self.download(&mut inner, value, data)
+            }
+            (CLASS_INTERFACE, DFU_CLRSTATUS) if inner.state == State::DfuError => {
+                inner.state = State::DfuIdle;
+                inner.status = Status::Ok;
+                Some(())
+            }
+            (CLASS_INTERFACE, DFU_ABORT)
+                if matches!(
+                    inner.state,
+                    State::DfuIdle | State::DfuDnloadIdle | State::DfuUploadIdle
+                ) =>
+            {
+                inner.state = State::DfuIdle;
+                Some(())
+            }
+            _ => None,
+        }
+        .ok_or_else(|| Self::stall(&mut inner))?;
+        Ok(data.len())
+    }
+
+    async fn reset(&self) -> Result<(), Error> {
+        if self.lock().hang_on_reset {
+            std::future::pending::<()>().await;
+        }
+        let mut inner = self.lock();
+        inner.resets += 1;
+        if inner.state == State::AppIdle {
+            return Ok(());
+        }
+        inner.state = State::DfuIdle;
+        inner.status = Status::Ok;
+        inner.pending = None;
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+//! Errors reported by the device through DFU_GETSTATUS (DFU 1.1 Specification, Section 6.1.2).
+
+use crate::{Error, UsbTransport, runtime};
+use dfu_core::{State, Status};
+use std::fmt;
+use std::time::Duration;
+
+/// An error the device reported in dfuERROR, with the block it failed on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceError {
+    /// bStatus, e.g. [`Status::ErrWrite`].
+    pub status: Status,
+    /// bState, dfuERROR unless the device reports errors in another state.
+    pub state: State,
+    /// The wValue of the DFU_DNLOAD or DFU_UPLOAD that failed, block 0 being a command on
+    /// DfuSe devices.
+    pub block: Option<u16>,
+    /// The string descriptor at iString, the device's own description of the error.
+    pub description: Option<String>,
+}
+
+impl DeviceError {
+    /// The name the DFU specification gives to bStatus, e.g. `errWRITE`.
+    pub fn status_name(&self) -> String {
+        let name = match self.status {
+            Status::Ok => "OK",
+            Status::ErrTarget => "errTARGET",
+            Status::ErrFile => "errFILE",
+            Status::ErrWrite => "errWRITE",
+            Status::ErrErase => "errERASE",
+            Status::ErrCheckErased => "errCHECK_ERASED",
+            Status::ErrProg => "errPROG",
+            Status::ErrVerify => "errVERIFY",
+            Status::ErrAddress => "errADDRESS",
+            Status::ErrNotdone => "errNOTDONE",
+            Status::ErrFirmware => "errFIRMWARE",
+            Status::ErrVendor => "errVENDOR",
+            Status::ErrUsbr => "errUSBR",
+            Status::ErrPor => "errPOR",
+            Status::ErrUnknown => "errUNKNOWN",
+            Status::ErrStalledpkt => "errSTALLEDPKT",
+            Status::Other(status) => return format!("bStatus {status:#04x}"),
+        };
+        name.to_string()
+    }
+
+    /// What went wrong and what can be done about it.
+    fn explanation(&self) -> (&'static str, Option<&'static str>) {
+        match self.status {
+            Status::ErrTarget => (
+                "The firmware is not for this device",
+                Some("Check that it was built for this device."),
+            ),
+            Status::ErrFile => (
+                "The device rejected the firmware",
+                Some("Check that it is signed or packaged for this device."),
+            ),
+            Status::ErrWrite => (
+                "The device could not write its memory",
+                Some("The memory may be write protected."),
+            ),
+            Status::ErrErase => (
+                "The device could not erase its memory",
+                Some("The memory may be write or read protected."),
+            ),
+            Status::ErrCheckErased => (
+                "The memory is not erased",
+                Some("The memory may be write protected."),
+            ),
+            Status::ErrProg => (
+                "The device could not program its memory",
+                Some("The memory may be write protected."),
+            ),
+            Status::ErrVerify => (
+                "The programmed memory failed verification",
+                Some("Download the firmware again."),
+            ),
+            Status::ErrAddress => (
+                "The address is outside the memory of the device",
+                Some("Check the load address of the firmware."),
+            ),
+            Status::ErrNotdone => (
+                "The device expected more firmware",
+                Some("The firmware may be truncated."),
+            ),
+            Status::ErrFirmware => (
+                "The firmware of the device is corrupt",
+                Some("Download a working firmware before leaving DFU mode."),
+            ),
+            Status::ErrVendor => ("The device reported a vendor specific error", None),
+            Status::ErrUsbr => (
+                "The device detected an unexpected USB reset",
+                Some("Check the cable and hub."),
+            ),
+            Status::ErrPor => (
+                "The device detected an unexpected power-on reset",
+                Some("Check its power supply."),
+            ),
+            Status::ErrStalledpkt => ("The device stalled an unexpected request", None),
+            Status::Ok | Status::ErrUnknown | Status::Other(_) => {
+                ("The device reported an error", None)
+            }
+        }
+    }
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (what, hint) = self.explanation();
+        f.write_str(what)?;
+        if let Some(block) = self.block {
+            write!(f, " at block {block}")?;
+        }
+        write!(f, " ({})", self.status_name())?;
+        if let Some(description) = &self.description {
+            write!(f, ": {description}")?;
+        }
+        if let Some(hint) = hint {
+            write!(f, ". {hint}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for DeviceError {}
+
+/// Turn a DFU_GETSTATUS response reporting dfuERROR after the DFU_DNLOAD or DFU_UPLOAD of
+/// `block` into an [`Error::Device`], reading the description at iString within `timeout`.
+pub(crate) async fn check<T: UsbTransport>(
+    transport: &T,
+    block: Option<u16>,
+    status: &[u8],
+    timeout: Duration,
+) -> Result<(), Error> {
+    let (Some(block), [status, _, _, _, state, index, ..]) = (block, status) else {
+        return Ok(());
+    };
+    let state = State::from(*state);
+    if state != State::DfuError {
+        return Ok(());
+    }
+    let description = match index {
+        0 => None,
+        &index => runtime::timeout(timeout, transport.string_descriptor(index))
+            .await
+            .ok()
+            .flatten()
+            .and_then(Result::ok),
+    };
+    Err(Error::Device(DeviceError {
+        status: Status::from(*status),
+        state,
+        block: Some(block),
+        description,
+    }))
+}
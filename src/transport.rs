@@ -0,0 +1,272 @@
+//! USB transports that [`DfuCrossUsb`](crate::DfuCrossUsb) can drive.
+//!
+//! A transport only has to perform control transfers and reset the device. String and
+//! configuration descriptors have default implementations on top of
+//! [`UsbTransport::control_in`], which transports with cached descriptors may override.
+
+use crate::Error;
+use cross_usb::usb::{
+    ControlIn, ControlOut, ControlType, Recipient, UsbDevice, UsbDeviceInfo, UsbInterface,
+};
+use usb::{descriptor_type, language_id, request_type, standard_request};
+
+/// `Send` on native targets, implemented by every type on WASM.
+#[cfg(not(target_family = "wasm"))]
+pub trait MaybeSend: Send {}
+#[cfg(not(target_family = "wasm"))]
+impl<T: Send + ?Sized> MaybeSend for T {}
+
+/// `Send` on native targets, implemented by every type on WASM.
+#[cfg(target_family = "wasm")]
+pub trait MaybeSend {}
+#[cfg(target_family = "wasm")]
+impl<T: ?Sized> MaybeSend for T {}
+
+/// `Sync` on native targets, implemented by every type on WASM.
+#[cfg(not(target_family = "wasm"))]
+pub trait MaybeSync: Sync {}
+#[cfg(not(target_family = "wasm"))]
+impl<T: Sync + ?Sized> MaybeSync for T {}
+
+/// `Sync` on native targets, implemented by every type on WASM.
+#[cfg(target_family = "wasm")]
+pub trait MaybeSync {}
+#[cfg(target_family = "wasm")]
+impl<T: ?Sized> MaybeSync for T {}
+
+/// A USB transport for a device with a claimed interface.
+///
+/// `request_type`, `request`, `value` and `index` are the `bmRequestType`, `bRequest`, `wValue`
+/// and `wIndex` fields of the SETUP packet.
+pub trait UsbTransport: MaybeSend + MaybeSync {
+    /// A control IN transfer (device to host) reading at most `length` bytes.
+    fn control_in(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> impl Future<Output = Result<Vec<u8>, Error>> + MaybeSend;
+
+    /// A control OUT transfer (host to device), returning the number of bytes written.
+    fn control_out(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+    ) -> impl Future<Output = Result<usize, Error>> + MaybeSend;
+
+    /// Reset the device.
+    fn reset(&self) -> impl Future<Output = Result<(), Error>> + MaybeSend;
+
+    /// Read the string descriptor at `index`, in the first language supported by the device.
+    fn string_descriptor(
+        &self,
+        index: u8,
+    ) -> impl Future<Output = Result<String, Error>> + MaybeSend {
+        async move {
+            // String descriptor zero holds the supported LANGIDs.
+            let languages = self.descriptor(descriptor_type::STRING, 0, 0, 255).await?;
+            let language = languages
+                .get(2..4)
+                .map(|id| u16::from_le_bytes([id[0], id[1]]))
+                .unwrap_or(language_id::ENGLISH_US);
+
+            let bytes = self
+                .descriptor(descriptor_type::STRING, index, language, 255)
+                .await?;
+            let len = bytes
+                .first()
+                .map_or(0, |&len| len as usize)
+                .min(bytes.len());
+            let utf16: Vec<u16> = bytes
+                .get(2..len)
+                .unwrap_or_default()
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .collect();
+            Ok(String::from_utf16_lossy(&utf16))
+        }
+    }
+
+    /// Read the complete configuration descriptor at `index`, including the interface, endpoint
+    /// and class specific descriptors that follow it.
+    fn configuration_descriptor(
+        &self,
+        index: u8,
+    ) -> impl Future<Output = Result<Vec<u8>, Error>> + MaybeSend {
+        async move {
+            let header = self
+                .descriptor(descriptor_type::CONFIGURATION, index, 0, 9)
+                .await?;
+            let total_length = header
+                .get(2..4)
+                .map(|len| u16::from_le_bytes([len[0], len[1]]))
+                .ok_or(Error::ConfigurationDescriptorNotFound)?;
+            self.descriptor(descriptor_type::CONFIGURATION, index, 0, total_length)
+                .await
+        }
+    }
+
+    /// Read a descriptor with a standard device GET_DESCRIPTOR request.
+    /// <https://www.beyondlogic.org/usbnutshell/usb6.shtml#StandardDeviceRequests>
+    fn descriptor(
+        &self,
+        descriptor_type: u8,
+        index: u8,
+        language_id: u16,
+        length: u16,
+    ) -> impl Future<Output = Result<Vec<u8>, Error>> + MaybeSend {
+        self.control_in(
+            request_type::direction::IN
+                | request_type::request_type::STANDARD
+                | request_type::recipient::DEVICE,
+            standard_request::GET_DESCRIPTOR,
+            ((descriptor_type as u16) << 8) | index as u16,
+            language_id,
+            length,
+        )
+    }
+}
+
+/// [`UsbTransport`] backed by a `cross_usb` device, working on both WASM and native targets.
+pub struct CrossUsbTransport {
+    device: cross_usb::Device,
+    interface: cross_usb::Interface,
+    interface_number: u8,
+}
+
+/// The highest interface number tried by [`CrossUsbTransport::open_any`].
+const MAX_INTERFACE_NUMBER: u8 = 31;
+
+impl CrossUsbTransport {
+    /// Open a USB device and claim one of its interfaces.
+    pub async fn open(
+        device_info: cross_usb::DeviceInfo,
+        interface_number: u8,
+    ) -> Result<Self, Error> {
+        let device = device_info.open().await?;
+        let interface = device.open_interface(interface_number).await?;
+
+        Ok(Self {
+            device,
+            interface,
+            interface_number,
+        })
+    }
+
+    /// Open a USB device and claim the first interface that can be claimed, skipping those
+    /// held by a kernel driver, e.g. the CDC ACM interfaces of a composite device.
+    pub(crate) async fn open_any(device_info: cross_usb::DeviceInfo) -> Result<Self, Error> {
+        let device = device_info.open().await?;
+        let mut error = None;
+        for interface_number in 0..=MAX_INTERFACE_NUMBER {
+            match device.open_interface(interface_number).await {
+                Ok(interface) => {
+                    return Ok(Self {
+                        device,
+                        interface,
+                        interface_number,
+                    });
+                }
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        Err(error.map_or(Error::DeviceNotFound, Error::from))
+    }
+
+    /// Claim another interface of the device, releasing the current one.
+    pub async fn claim_interface(&mut self, interface_number: u8) -> Result<(), Error> {
+        self.interface = self.device.open_interface(interface_number).await?;
+        self.interface_number = interface_number;
+        Ok(())
+    }
+
+    /// The number of the claimed interface.
+    pub fn interface_number(&self) -> u8 {
+        self.interface_number
+    }
+
+    /// The underlying `cross_usb` device.
+    pub fn device(&self) -> &cross_usb::Device {
+        &self.device
+    }
+
+    /// The claimed `cross_usb` interface.
+    pub fn interface(&self) -> &cross_usb::Interface {
+        &self.interface
+    }
+}
+
+impl UsbTransport for CrossUsbTransport {
+    async fn control_in(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> Result<Vec<u8>, Error> {
+        let (control_type, recipient) = split_request_type(request_type);
+        Ok(self
+            .interface
+            .control_in(ControlIn {
+                control_type,
+                recipient,
+                request,
+                value,
+                index,
+                length,
+            })
+            .await?)
+    }
+
+    async fn control_out(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+    ) -> Result<usize, Error> {
+        let (control_type, recipient) = split_request_type(request_type);
+        Ok(self
+            .interface
+            .control_out(ControlOut {
+                control_type,
+                recipient,
+                request,
+                value,
+                index,
+                data,
+            })
+            .await?)
+    }
+
+    async fn reset(&self) -> Result<(), Error> {
+        Ok(self.device.reset().await?)
+    }
+}
+
+fn split_request_type(request_type: u8) -> (ControlType, Recipient) {
+    (
+        match request_type >> 5 & 0x03 {
+            0 => ControlType::Standard,
+            1 => ControlType::Class,
+            2 => ControlType::Vendor,
+            _ => ControlType::Standard,
+        },
+        match request_type & 0x1f {
+            0 => Recipient::Device,
+            1 => Recipient::Interface,
+            2 => Recipient::Endpoint,
+            3 => Recipient::Other,
+            _ => Recipient::Device,
+        },
+    )
+}
@@ -0,0 +1,139 @@
+//! Reading the firmware of a device with DFU_UPLOAD (DFU 1.1 Specification, Section 6.2).
+
+use crate::request::DFUSE_FIRST_BLOCK;
+use crate::{DfuCrossUsb, DfuseCommand, Error, UsbTransport};
+use dfu_core::DfuProtocol;
+#[cfg(not(target_family = "wasm"))]
+use futures::executor::block_on;
+#[cfg(not(target_family = "wasm"))]
+use futures::io::AllowStdIo;
+use futures::io::{AsyncWrite, AsyncWriteExt};
+
+/// Options of [`DfuCrossUsb::upload`].
+#[derive(Debug, Clone, Default)]
+pub struct UploadOptions {
+    max_length: Option<usize>,
+    address: Option<u32>,
+}
+
+impl UploadOptions {
+    /// Options reading the whole firmware from the start of the memory.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop after `max_length` bytes, even if the device has more to send.
+    pub fn with_max_length(mut self, max_length: usize) -> Self {
+        self.max_length = Some(max_length);
+        self
+    }
+
+    /// Read a DfuSe device from `address` rather than from the start of the memory region of
+    /// the alternate setting. DFU 1.1 devices have no addresses and ignore it.
+    pub fn with_address(mut self, address: u32) -> Self {
+        self.address = Some(address);
+        self
+    }
+}
+
+impl<T: UsbTransport + 'static> DfuCrossUsb<T> {
+    /// Read the firmware of the device into `writer`, returning the number of bytes read.
+    ///
+    /// The device signals the end of the firmware with a block shorter than wTransferSize,
+    /// so the size of the firmware need not be known. DfuSe devices are read up to the end of
+    /// the memory region of the alternate setting at most.
+    pub async fn upload<W: AsyncWrite + Unpin>(
+        &self,
+        mut writer: W,
+        options: &UploadOptions,
+    ) -> Result<usize, Error> {
+        self.expect_idle().await?;
+
+        let mut max_length = options.max_length;
+        let first_block = match &self.protocol {
+            DfuProtocol::Dfuse {
+                address: start,
+                memory_layout,
+            } => {
+                let address = options.address.unwrap_or(*start);
+                // STM32 bootloaders do not end the memory with a short frame, the upload stops
+                // at the end of the memory region.
+                let end = *start as u64
+                    + memory_layout
+                        .as_slice()
+                        .iter()
+                        .map(|&size| size as u64)
+                        .sum::<u64>();
+                if address < *start || address as u64 >= end {
+                    return Err(Error::AddressOutOfRange { address, length: 0 });
+                }
+                let available = (end - address as u64) as usize;
+                max_length = Some(max_length.map_or(available, |length| length.min(available)));
+                self.dfuse_command(DfuseCommand::SetAddress(address))
+                    .await?;
+                // DFU_UPLOAD is only accepted in dfuIDLE.
+                self.abort().await?;
+                DFUSE_FIRST_BLOCK
+            }
+            DfuProtocol::Dfu => 0,
+        };
+        self.upload_blocks(first_block, max_length, &mut writer)
+            .await
+    }
+
+    /// Read the firmware of the device into a `std::io::Write`, see [`upload`](Self::upload).
+    ///
+    /// Like [`DfuSync`](crate::DfuSync), this blocks on the USB transfers and is therefore not
+    /// available on WASM.
+    #[cfg(not(target_family = "wasm"))]
+    pub fn upload_blocking<W: std::io::Write>(
+        &self,
+        writer: W,
+        options: &UploadOptions,
+    ) -> Result<usize, Error> {
+        block_on(self.upload(AllowStdIo::new(writer), options))
+    }
+
+    /// Read blocks from `first_block` on until a short frame or `max_length` bytes, leaving the
+    /// device in dfuIDLE.
+    pub(crate) async fn upload_blocks<W: AsyncWrite + Unpin>(
+        &self,
+        first_block: u16,
+        max_length: Option<usize>,
+        writer: &mut W,
+    ) -> Result<usize, Error> {
+        let transfer_size = self.descriptor.transfer_size as usize;
+        let mut length = 0;
+        let mut block_num = first_block;
+        loop {
+            if max_length.is_some_and(|max_length| length >= max_length) {
+                // The device expects more DFU_UPLOAD requests in dfuUPLOAD-IDLE.
+                self.abort().await?;
+                break;
+            }
+
+            // The last block only asks for what is left, so that progress counts what is read.
+            let request_length = max_length.map_or(transfer_size, |max_length| {
+                transfer_size.min(max_length - length)
+            });
+            let block = self.upload_block(block_num, request_length).await?;
+            writer.write_all(&block).await?;
+            length += block.len();
+            // A short frame ends the upload and returns the device to dfuIDLE.
+            if block.len() < request_length {
+                break;
+            }
+
+            block_num = if self.is_dfuse() {
+                // DfuSe derives the address from the block number, which therefore cannot wrap.
+                block_num
+                    .checked_add(1)
+                    .ok_or(dfu_core::Error::MaximumChunksExceeded)?
+            } else {
+                block_num.wrapping_add(1)
+            };
+        }
+        writer.flush().await?;
+        Ok(length)
+    }
+}
@@ -0,0 +1,1754 @@
+use dfu_core::asynchronous::DfuAsyncIo;
+use dfu_core::{State, Status};
+use dfu_cross_usb::discover::discover_transport;
+use dfu_cross_usb::firmware::{FileError, Segment};
+use dfu_cross_usb::sim::{self, SimulatedDevice};
+use dfu_cross_usb::{
+    DeviceIds, DfuCrossUsb, DfuFile, DfuMode, DfuRequest, DfuseCommand, DfuseFile, DownloadOptions,
+    ElfFirmware, ErasePage, Error, FlashPlan, GapFill, PageHash, Phase, Progress, RecoveryAction,
+    SparseFirmware, UploadOptions,
+};
+use dfu_cross_usb::{OptionBytes, OptionBytesError, ReadProtection, Stm32Family, UserOptions};
+use futures::executor::block_on;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+fn image(len: usize) -> Vec<u8> {
+    (0..len).map(|i| i as u8).collect()
+}
+
+/// Append a DFU suffix for `vendor_id:product_id` to `firmware`.
+fn with_suffix(firmware: &[u8], vendor_id: u16, product_id: u16) -> Vec<u8> {
+    let mut file = firmware.to_vec();
+    for field in [0x0100, product_id, vendor_id, 0x0100] {
+        file.extend_from_slice(&u16::to_le_bytes(field));
+    }
+    file.extend_from_slice(b"UFD\x10");
+    let crc = file.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())
+        })
+    });
+    file.extend_from_slice(&crc.to_le_bytes());
+    file
+}
+
+/// A DfuSe target of `(alt_setting, [(address, data)])`.
+type Target<'a> = (u8, &'a [(u32, &'a [u8])]);
+
+/// Build a DfuSe file without its DFU suffix.
+fn dfuse_image(targets: &[Target]) -> Vec<u8> {
+    let mut file = b"DfuSe\x01\0\0\0\0".to_vec();
+    file.push(targets.len() as u8);
+    for &(alt_setting, elements) in targets {
+        file.extend_from_slice(b"Target");
+        file.push(alt_setting);
+        file.extend_from_slice(&1u32.to_le_bytes());
+        let mut name = format!("Target {alt_setting}").into_bytes();
+        name.resize(255, 0);
+        file.extend_from_slice(&name);
+        let size: usize = elements.iter().map(|(_, data)| 8 + data.len()).sum();
+        file.extend_from_slice(&(size as u32).to_le_bytes());
+        file.extend_from_slice(&(elements.len() as u32).to_le_bytes());
+        for &(address, data) in elements {
+            file.extend_from_slice(&address.to_le_bytes());
+            file.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            file.extend_from_slice(data);
+        }
+    }
+    let image_size = (file.len() as u32).to_le_bytes();
+    file[6..10].copy_from_slice(&image_size);
+    file
+}
+
+/// An Intel HEX record.
+fn hex_record(kind: u8, offset: u16, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&offset.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend_from_slice(data);
+    let sum = bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    bytes.push(sum.wrapping_neg());
+    let digits: String = bytes.iter().map(|b| format!("{b:02X}")).collect();
+    format!(":{digits}\n")
+}
+
+/// An S3 record, with a 32 bit address.
+fn srec_record(address: u32, data: &[u8]) -> String {
+    let mut bytes = vec![(data.len() + 5) as u8];
+    bytes.extend_from_slice(&address.to_be_bytes());
+    bytes.extend_from_slice(data);
+    let sum = bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    bytes.push(!sum);
+    let digits: String = bytes.iter().map(|b| format!("{b:02X}")).collect();
+    format!("S3{digits}\n")
+}
+
+/// A 32 bit little-endian ELF executable with a section and a PT_LOAD segment for each of
+/// `(name, virtual address, physical address, data)`.
+fn elf32(sections: &[(&str, u32, u32, &[u8])]) -> Vec<u8> {
+    let u16 = |file: &mut Vec<u8>, value: u16| file.extend_from_slice(&value.to_le_bytes());
+    let u32 = |file: &mut Vec<u8>, value: u32| file.extend_from_slice(&value.to_le_bytes());
+    let data_offset = 52 + 32 * sections.len() as u32;
+    let mut names = b"\0.shstrtab\0".to_vec();
+    let mut data = Vec::new();
+    let mut program_headers = Vec::new();
+    let mut section_headers = vec![0; 40];
+    for &(name, virtual_address, physical_address, bytes) in sections {
+        let offset = data_offset + data.len() as u32;
+        let size = bytes.len() as u32;
+        for value in [
+            1,
+            offset,
+            virtual_address,
+            physical_address,
+            size,
+            size,
+            5,
+            4,
+        ] {
+            u32(&mut program_headers, value);
+        }
+        let name_offset = names.len() as u32;
+        for value in [name_offset, 1, 6, virtual_address, offset, size, 0, 0, 4, 0] {
+            u32(&mut section_headers, value);
+        }
+        names.extend_from_slice(name.as_bytes());
+        names.push(0);
+        data.extend_from_slice(bytes);
+    }
+    let names_offset = data_offset + data.len() as u32;
+    for value in [1, 3, 0, 0, names_offset, names.len() as u32, 0, 0, 1, 0] {
+        u32(&mut section_headers, value);
+    }
+
+    let mut file = b"\x7fELF\x01\x01\x01\0\0\0\0\0\0\0\0\0".to_vec();
+    u16(&mut file, 2);
+    u16(&mut file, 40);
+    for value in [1, 0x0800_0000, 52, names_offset + names.len() as u32, 0] {
+        u32(&mut file, value);
+    }
+    for value in [
+        52,
+        32,
+        sections.len() as u16,
+        40,
+        sections.len() as u16 + 2,
+        sections.len() as u16 + 1,
+    ] {
+        u16(&mut file, value);
+    }
+    file.extend_from_slice(&program_headers);
+    file.extend_from_slice(&data);
+    file.extend_from_slice(&names);
+    file.extend_from_slice(&section_headers);
+    file
+}
+
+#[test]
+fn async_download_writes_flash() {
+    let image = image(3000);
+    let device = SimulatedDevice::new(4096)
+        .with_transfer_size(256)
+        .with_busy_polls(2);
+
+    let device = block_on(async {
+        let mut dfu = DfuCrossUsb::from_transport(device, 0, 0)
+            .await?
+            .into_async_dfu();
+        dfu.download_from_slice(&image).await?;
+        Ok::<_, Error>(dfu.into_inner())
+    })
+    .unwrap();
+
+    let sim = device.transport();
+    assert_eq!(&sim.flash()[..image.len()], &image[..]);
+    assert_eq!(sim.state(), State::DfuIdle);
+    assert_eq!(sim.resets(), 0);
+}
+
+#[test]
+fn sync_download_resets_device_that_is_not_manifestation_tolerant() {
+    let image = image(100);
+    let device = SimulatedDevice::new(1024)
+        .with_transfer_size(64)
+        .with_attributes(sim::CAN_DOWNLOAD);
+
+    let device = block_on(DfuCrossUsb::from_transport(device, 0, 0)).unwrap();
+    let mut dfu = device.into_sync_dfu();
+    dfu.download_from_slice(&image).unwrap();
+
+    let sim = dfu.into_inner();
+    let sim = sim.transport();
+    assert_eq!(&sim.flash()[..image.len()], &image[..]);
+    assert_eq!(sim.resets(), 1);
+}
+
+#[test]
+fn zero_transfer_size_is_refused() {
+    let device = SimulatedDevice::new(1024).with_transfer_size(0);
+
+    let result = block_on(DfuCrossUsb::from_transport(device, 0, 0));
+
+    assert!(matches!(result, Err(Error::ZeroTransferSize)));
+}
+
+#[test]
+fn failed_block_aborts_download() {
+    let device = SimulatedDevice::new(4096).with_transfer_size(256);
+    device.fail_block(3, Status::ErrWrite);
+
+    let (result, device) = block_on(async {
+        let mut dfu = DfuCrossUsb::from_transport(device, 0, 0)
+            .await
+            .unwrap()
+            .into_async_dfu();
+        let result = dfu.download_from_slice(&image(2048)).await;
+        (result, dfu.into_inner())
+    });
+
+    let Err(Error::Device(error)) = result else {
+        panic!("expected a device error, got {result:?}");
+    };
+    assert_eq!(error.status, Status::ErrWrite);
+    assert_eq!(error.state, State::DfuError);
+    assert_eq!(error.block, Some(3));
+    assert_eq!(device.transport().status(), Status::ErrWrite);
+}
+
+#[test]
+fn device_error_carries_description_and_does_not_block_next_download() {
+    let device = SimulatedDevice::new(1024)
+        .with_transfer_size(256)
+        .with_error_string("Flash locked");
+    let handle = device.clone();
+    handle.fail_block(1, Status::ErrVendor);
+
+    let (result, device) = block_on(async {
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await.unwrap();
+        let result = device.download(&[0x55; 512], &DownloadOptions::new()).await;
+        (result, device)
+    });
+
+    let Err(Error::Device(error)) = result else {
+        panic!("expected a device error, got {result:?}");
+    };
+    assert_eq!(error.description.as_deref(), Some("Flash locked"));
+    assert_eq!(error.status_name(), "errVENDOR");
+    assert_eq!(
+        error.to_string(),
+        "The device reported a vendor specific error at block 1 (errVENDOR): Flash locked"
+    );
+
+    // dfu_core clears the error left behind before downloading again.
+    let mut dfu = device.into_async_dfu();
+    block_on(dfu.download_from_slice(&[0xaa; 256])).unwrap();
+    assert_eq!(&handle.flash()[..256], &[0xaa; 256]);
+}
+
+#[test]
+fn failed_block_aborts_download_after_busy_polls() {
+    let device = SimulatedDevice::new(4096)
+        .with_transfer_size(256)
+        .with_busy_polls(1);
+    device.fail_block(3, Status::ErrWrite);
+
+    let result = block_on(async {
+        let mut dfu = DfuCrossUsb::from_transport(device, 0, 0)
+            .await
+            .unwrap()
+            .into_async_dfu();
+        dfu.download_from_slice(&image(2048)).await
+    });
+
+    let Err(Error::Device(error)) = result else {
+        panic!("expected a device error, got {result:?}");
+    };
+    assert_eq!(error.status, Status::ErrWrite);
+    assert_eq!(error.state, State::DfuError);
+    assert_eq!(error.block, Some(3));
+}
+
+#[test]
+fn device_error_after_busy_polls_carries_description_and_does_not_block_next_download() {
+    let device = SimulatedDevice::new(1024)
+        .with_transfer_size(256)
+        .with_busy_polls(2)
+        .with_error_string("Flash locked");
+    let handle = device.clone();
+    handle.fail_block(1, Status::ErrVendor);
+
+    let (result, device) = block_on(async {
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await.unwrap();
+        let result = device.download(&[0x55; 512], &DownloadOptions::new()).await;
+        (result, device)
+    });
+
+    let Err(Error::Device(error)) = result else {
+        panic!("expected a device error, got {result:?}");
+    };
+    assert_eq!(error.block, Some(1));
+    assert_eq!(
+        error.to_string(),
+        "The device reported a vendor specific error at block 1 (errVENDOR): Flash locked"
+    );
+
+    let mut dfu = device.into_async_dfu();
+    block_on(dfu.download_from_slice(&[0xaa; 256])).unwrap();
+    assert_eq!(&handle.flash()[..256], &[0xaa; 256]);
+}
+
+#[test]
+fn upload_reads_flash_until_short_frame() {
+    let flash = image(300);
+    let device = SimulatedDevice::new(0).with_flash(flash.clone());
+
+    let uploaded = block_on(async {
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        let mut uploaded = Vec::new();
+        let mut buffer = [0; 128];
+        for block_num in 0.. {
+            let n = DfuAsyncIo::read_control(&device, 0xa1, 2, block_num, &mut buffer).await?;
+            uploaded.extend_from_slice(&buffer[..n]);
+            if n < buffer.len() {
+                break;
+            }
+        }
+        Ok::<_, Error>(uploaded)
+    })
+    .unwrap();
+
+    assert_eq!(uploaded, flash);
+}
+
+#[test]
+fn invalid_request_stalls_into_error_state() {
+    let device = SimulatedDevice::new(1024);
+
+    let device = block_on(async {
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        // A zero length DFU_DNLOAD is not allowed in dfuIDLE.
+        let result = DfuAsyncIo::write_control(&device, 0x21, 1, 0, &[]).await;
+        assert!(matches!(result, Err(Error::WebUsb(_))));
+        Ok::<_, Error>(device)
+    })
+    .unwrap();
+
+    assert_eq!(device.transport().state(), State::DfuError);
+    assert_eq!(device.transport().status(), Status::ErrStalledpkt);
+}
+
+#[test]
+fn open_reads_functional_descriptor_from_configuration() {
+    let device = SimulatedDevice::new(1024).with_transfer_size(512);
+
+    let device = block_on(DfuCrossUsb::from_transport(device, 0, 0)).unwrap();
+    assert_eq!(device.functional_descriptor().transfer_size, 512);
+    assert_eq!(device.functional_descriptor().dfu_version, (1, 0x10));
+}
+
+#[test]
+fn open_parses_dfuse_memory_layout_from_interface_string() {
+    let device = SimulatedDevice::new(1024)
+        .with_dfu_version(0x011a)
+        .with_alt_settings([
+            "@Internal Flash  /0x08000000/04*016Kg,01*064Kg,07*128Kg",
+            "@Option Bytes  /0x1FFFC000/01*016 e",
+        ]);
+
+    let device = block_on(DfuCrossUsb::from_transport(device, 0, 1)).unwrap();
+    assert_eq!(
+        device.interface_string(),
+        "@Option Bytes  /0x1FFFC000/01*016 e"
+    );
+    match device.protocol() {
+        dfu_core::DfuProtocol::Dfuse {
+            address,
+            memory_layout,
+        } => {
+            assert_eq!(*address, 0x1fffc000);
+            assert_eq!(memory_layout.as_slice(), &[16]);
+        }
+        dfu_core::DfuProtocol::Dfu => panic!("expected DfuSe protocol"),
+    }
+}
+
+#[test]
+fn discover_lists_every_alternate_setting() {
+    let device = SimulatedDevice::new(1024)
+        .with_transfer_size(2048)
+        .with_alt_settings([
+            "@Internal Flash  /0x08000000/064*2Kg",
+            "@Option Bytes  /0x1FFFF800/01*016 e",
+        ]);
+
+    let interfaces = block_on(discover_transport(&device)).unwrap();
+    assert_eq!(interfaces.len(), 2);
+    for (alt, interface) in interfaces.iter().enumerate() {
+        assert_eq!(interface.configuration, 1);
+        assert_eq!(interface.interface_number, 0);
+        assert_eq!(interface.alternate_setting, alt as u8);
+        assert_eq!(interface.mode, DfuMode::Dfu);
+        let descriptor = interface.functional_descriptor.unwrap();
+        assert_eq!(descriptor.transfer_size, 2048);
+    }
+    assert_eq!(
+        interfaces[1].name.as_deref(),
+        Some("@Option Bytes  /0x1FFFF800/01*016 e")
+    );
+}
+
+#[test]
+fn detach_reopens_runtime_device_in_dfu_mode() {
+    let device = SimulatedDevice::new(1024).with_runtime_mode();
+    let handle = device.clone();
+
+    let device = block_on(async {
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        assert_eq!(device.mode(), DfuMode::Runtime);
+        device
+            .detach_and_reopen_with(0, || {
+                let handle = handle.clone();
+                async move { Ok(Some(handle)) }
+            })
+            .await
+    })
+    .unwrap();
+
+    assert_eq!(device.mode(), DfuMode::Dfu);
+    assert_eq!(device.transport().state(), State::DfuIdle);
+    assert_eq!(device.transport().resets(), 1);
+}
+
+#[test]
+fn detach_does_not_reset_device_that_will_detach() {
+    let device = SimulatedDevice::new(1024)
+        .with_runtime_mode()
+        .with_attributes(sim::CAN_DOWNLOAD | sim::WILL_DETACH)
+        .with_detach_timeout(0);
+    let handle = device.clone();
+
+    let device = block_on(async {
+        DfuCrossUsb::from_transport(device, 0, 0)
+            .await?
+            .detach_and_reopen_with(0, || {
+                let handle = handle.clone();
+                async move { Ok(Some(handle)) }
+            })
+            .await
+    })
+    .unwrap();
+
+    assert_eq!(device.mode(), DfuMode::Dfu);
+    assert_eq!(device.transport().resets(), 0);
+}
+
+#[test]
+fn detach_stops_when_several_devices_match() {
+    let device = SimulatedDevice::new(1024).with_runtime_mode();
+    let mut polls = 0;
+
+    let result = block_on(async {
+        DfuCrossUsb::from_transport(device, 0, 0)
+            .await?
+            .detach_and_reopen_with(0, || {
+                polls += 1;
+                async { Err::<Option<SimulatedDevice>, _>(Error::MultipleDevicesFound) }
+            })
+            .await
+    });
+
+    assert!(matches!(result, Err(Error::MultipleDevicesFound)));
+    assert_eq!(polls, 1);
+}
+
+#[test]
+fn download_reports_progress() {
+    let image = image(1000);
+    let device = SimulatedDevice::new(4096)
+        .with_transfer_size(256)
+        .with_busy_polls(1);
+    let events = Arc::new(Mutex::new(Vec::<Progress>::new()));
+
+    block_on(async {
+        let mut device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        let sink = events.clone();
+        device.with_progress(move |progress| sink.lock().unwrap().push(progress));
+        device.into_async_dfu().download_from_slice(&image).await
+    })
+    .unwrap();
+
+    let events = events.lock().unwrap();
+    let writes: Vec<_> = events
+        .iter()
+        .filter(|p| p.phase == Phase::Write && p.state == Some(State::DfuDnloadSync))
+        .map(|p| (p.block_number, p.bytes))
+        .collect();
+    assert_eq!(writes, [(0, 256), (1, 512), (2, 768), (3, 1000)]);
+    assert!(events.iter().any(|p| p.state == Some(State::DfuDnbusy)));
+    let last = events.last().unwrap();
+    assert_eq!(last.phase, Phase::Manifest);
+    assert_eq!(last.bytes, image.len());
+    assert_eq!(last.state, Some(State::DfuIdle));
+}
+
+#[test]
+fn sync_upload_reports_progress_as_stream() {
+    let device = SimulatedDevice::new(0).with_flash(image(300));
+
+    let mut device = block_on(DfuCrossUsb::from_transport(device, 0, 0)).unwrap();
+    let mut events = device.progress_stream();
+    let mut buffer = [0; 128];
+    for block_num in 0..3 {
+        dfu_core::DfuIo::read_control(&device, 0xa1, 2, block_num, &mut buffer).unwrap();
+    }
+    drop(device);
+
+    let events: Vec<_> = block_on(futures::StreamExt::collect::<Vec<_>>(&mut events));
+    let uploads: Vec<_> = events
+        .iter()
+        .map(|p| (p.phase, p.block_number, p.bytes, p.state))
+        .collect();
+    assert_eq!(
+        uploads,
+        [
+            (Phase::Upload, 0, 128, Some(State::DfuUploadIdle)),
+            (Phase::Upload, 1, 256, Some(State::DfuUploadIdle)),
+            (Phase::Upload, 2, 300, Some(State::DfuIdle)),
+        ]
+    );
+}
+
+#[test]
+fn download_verifies_firmware_after_manifestation() {
+    let image = image(3000);
+    let device = SimulatedDevice::new(4096).with_transfer_size(256);
+
+    let device = block_on(async {
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        let options = DownloadOptions::new().with_verify(true);
+        device.download(&image, &options).await?;
+        Ok::<_, Error>(device)
+    })
+    .unwrap();
+
+    assert_eq!(&device.transport().flash()[..image.len()], &image[..]);
+    assert_eq!(device.transport().state(), State::DfuIdle);
+}
+
+#[test]
+fn dfuse_download_erases_and_verifies_before_leaving() {
+    let image = image(2500);
+    let device = SimulatedDevice::new(4096)
+        .with_flash(vec![0; 4096])
+        .with_transfer_size(512)
+        .with_dfu_version(0x011a)
+        .with_alt_settings(["@Internal Flash  /0x08000000/04*001Kg"]);
+
+    let device = block_on(async {
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        let options = DownloadOptions::new().with_verify(true);
+        device.download(&image, &options).await?;
+        Ok::<_, Error>(device)
+    })
+    .unwrap();
+
+    let flash = device.transport().flash();
+    assert_eq!(&flash[..image.len()], &image[..]);
+    // The last page is only partially written, the rest of it is erased.
+    assert!(flash[image.len()..3072].iter().all(|&b| b == 0xff));
+    assert!(flash[3072..].iter().all(|&b| b == 0));
+}
+
+#[test]
+fn verify_reports_first_mismatch() {
+    let device = SimulatedDevice::new(4096)
+        .with_transfer_size(256)
+        .with_write_protection(1000..1100);
+
+    let result = block_on(async {
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        let options = DownloadOptions::new().with_verify(true);
+        device.download(&[0x55; 2000], &options).await
+    });
+
+    assert!(matches!(
+        result,
+        Err(Error::VerifyMismatch {
+            offset: 1000,
+            size: 100,
+            address: None
+        })
+    ));
+}
+
+#[test]
+fn verify_is_refused_without_upload_support() {
+    let device = SimulatedDevice::new(1024).with_attributes(sim::CAN_DOWNLOAD);
+    let handle = device.clone();
+
+    let result = block_on(async {
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        let options = DownloadOptions::new().with_verify(true);
+        device.download(&[0x55; 100], &options).await
+    });
+
+    assert!(matches!(result, Err(Error::VerifyNotSupported)));
+    assert!(handle.flash().iter().all(|&b| b == 0xff));
+}
+
+#[test]
+fn upload_detects_end_of_firmware_from_short_frame() {
+    let flash = image(300);
+    let device = SimulatedDevice::new(0)
+        .with_flash(flash.clone())
+        .with_transfer_size(128);
+
+    let (uploaded, device) = block_on(async {
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        let mut uploaded = Vec::new();
+        let len = device.upload(&mut uploaded, &UploadOptions::new()).await?;
+        assert_eq!(len, flash.len());
+        Ok::<_, Error>((uploaded, device))
+    })
+    .unwrap();
+
+    assert_eq!(uploaded, flash);
+    assert_eq!(device.transport().state(), State::DfuIdle);
+}
+
+#[test]
+fn upload_blocking_stops_at_max_length() {
+    let flash = image(1000);
+    let device = SimulatedDevice::new(0)
+        .with_flash(flash.clone())
+        .with_transfer_size(128);
+
+    let device = block_on(DfuCrossUsb::from_transport(device, 0, 0)).unwrap();
+    let mut uploaded = Vec::new();
+    let options = UploadOptions::new().with_max_length(200);
+    let len = device.upload_blocking(&mut uploaded, &options).unwrap();
+
+    assert_eq!(len, 200);
+    assert_eq!(uploaded, &flash[..200]);
+    assert_eq!(device.transport().state(), State::DfuIdle);
+}
+
+#[test]
+fn dfuse_upload_starts_at_address() {
+    let flash = image(4096);
+    let device = SimulatedDevice::new(0)
+        .with_flash(flash.clone())
+        .with_transfer_size(256)
+        .with_dfu_version(0x011a)
+        .with_alt_settings(["@Internal Flash  /0x08000000/04*001Kg"]);
+
+    let uploaded = block_on(async {
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        let mut uploaded = Vec::new();
+        let options = UploadOptions::new()
+            .with_address(0x0800_0400)
+            .with_max_length(600);
+        device.upload(&mut uploaded, &options).await?;
+        Ok::<_, Error>(uploaded)
+    })
+    .unwrap();
+
+    assert_eq!(uploaded, &flash[0x400..0x400 + 600]);
+}
+
+#[test]
+fn dfuse_upload_stops_at_end_of_memory() {
+    // Like STM32 bootloaders, the device sends full frames past the end of the memory.
+    let flash = image(8192);
+    let device = SimulatedDevice::new(0)
+        .with_flash(flash.clone())
+        .with_transfer_size(256)
+        .with_dfu_version(0x011a)
+        .with_alt_settings(["@Internal Flash  /0x08000000/04*001Kg"]);
+
+    let (whole, tail) = block_on(async {
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        let mut whole = Vec::new();
+        device.upload(&mut whole, &UploadOptions::new()).await?;
+        let mut tail = Vec::new();
+        let options = UploadOptions::new().with_address(0x0800_0c00);
+        device.upload(&mut tail, &options).await?;
+        Ok::<_, Error>((whole, tail))
+    })
+    .unwrap();
+
+    assert_eq!(whole, &flash[..4096]);
+    assert_eq!(tail, &flash[0xc00..4096]);
+}
+
+#[test]
+fn download_file_strips_suffix() {
+    let firmware = image(100);
+    let file = with_suffix(&firmware, 0x1209, 0xdf11);
+    let device = SimulatedDevice::new(1024);
+
+    let device = block_on(async {
+        let file = DfuFile::parse(&file)?;
+        assert_eq!(file.suffix().dfu_version, 0x0100);
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        device.download_file(&file, &DownloadOptions::new()).await?;
+        Ok::<_, Error>(device)
+    })
+    .unwrap();
+
+    let flash = device.transport().flash();
+    assert_eq!(&flash[..firmware.len()], &firmware[..]);
+    assert!(flash[firmware.len()..].iter().all(|&b| b == 0xff));
+}
+
+#[test]
+fn file_with_corrupted_crc_is_rejected() {
+    let mut file = with_suffix(&image(100), 0x1209, 0xdf11);
+    file[10] ^= 1;
+
+    assert!(matches!(
+        DfuFile::parse(&file),
+        Err(FileError::CrcMismatch { .. })
+    ));
+    assert!(matches!(
+        DfuFile::parse(&image(100)),
+        Err(FileError::MissingSuffix)
+    ));
+}
+
+#[test]
+fn file_for_other_device_is_refused_unless_wildcard() {
+    let firmware = image(100);
+    let device = SimulatedDevice::new(1024).with_ids(0x1209, 0x0001, 0x0100);
+    let handle = device.clone();
+
+    block_on(async {
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+
+        let file = with_suffix(&firmware, 0x1209, 0xdf11);
+        let result = device
+            .download_file(&DfuFile::parse(&file)?, &DownloadOptions::new())
+            .await;
+        match result {
+            Err(Error::DeviceMismatch { file, device }) => {
+                assert_eq!(file.product_id, 0xdf11);
+                assert_eq!(
+                    device,
+                    DeviceIds {
+                        vendor_id: 0x1209,
+                        product_id: 0x0001,
+                        bcd_device: 0x0100
+                    }
+                );
+            }
+            other => panic!("expected a device mismatch, got {other:?}"),
+        }
+        assert!(handle.flash().iter().all(|&b| b == 0xff));
+
+        let file = with_suffix(&firmware, 0x1209, DeviceIds::WILDCARD);
+        device
+            .download_file(&DfuFile::parse(&file)?, &DownloadOptions::new())
+            .await
+    })
+    .unwrap();
+
+    assert_eq!(&handle.flash()[..firmware.len()], &firmware[..]);
+}
+
+#[test]
+fn dfuse_file_is_flashed_to_each_alt_setting() {
+    let low = image(300);
+    let high = [0x5a; 100];
+    let option_bytes = [0xaa, 0x55, 0xff, 0x00, 0xf0, 0x0f, 0x00, 0xff];
+    let file = with_suffix(
+        &dfuse_image(&[
+            (0, &[(0x0800_0000, &low), (0x0800_0200, &high)]),
+            (1, &[(0x1fff_c000, &option_bytes)]),
+        ]),
+        0x1209,
+        0xdf11,
+    );
+    let device = SimulatedDevice::new(2048)
+        .with_flash(vec![0; 2048])
+        .with_transfer_size(128)
+        .with_dfu_version(0x011a)
+        .with_alt_settings([
+            "@Internal Flash  /0x08000000/02*001Kg",
+            "@Option Bytes  /0x1FFFC000/01*016 e",
+        ])
+        .with_memory(1, vec![0; 16]);
+    let handle = device.clone();
+    let events = Arc::new(Mutex::new(Vec::new()));
+
+    block_on(async {
+        let file = DfuseFile::parse(&file)?;
+        assert_eq!(file.targets().len(), 2);
+        assert_eq!(file.targets()[1].name.as_deref(), Some("Target 1"));
+
+        let mut device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        let sink = events.clone();
+        device.with_progress(move |progress| sink.lock().unwrap().push(progress));
+        let options = DownloadOptions::new().with_verify(true);
+        device.download_dfuse_file(&file, &options).await
+    })
+    .unwrap();
+
+    let flash = handle.flash();
+    assert_eq!(&flash[..300], &low[..]);
+    // Both elements share the first page, which is erased once.
+    assert!(flash[300..0x200].iter().all(|&b| b == 0xff));
+    assert_eq!(&flash[0x200..0x264], &high[..]);
+    assert!(flash[1024..].iter().all(|&b| b == 0));
+    assert_eq!(&handle.memory(1)[..8], &option_bytes[..]);
+    // The device left DFU mode from the first target.
+    assert_eq!(handle.alt_setting(), 0);
+
+    let events = events.lock().unwrap();
+    let last = |phase| {
+        events
+            .iter()
+            .rfind(|p: &&Progress| p.phase == phase)
+            .unwrap()
+    };
+    assert_eq!(last(Phase::Write).bytes, 408);
+    assert_eq!(last(Phase::Write).total, Some(408));
+    assert_eq!(last(Phase::Upload).bytes, 408);
+    let leaves = events
+        .iter()
+        .filter(|p| p.state == Some(State::DfuManifestSync))
+        .count();
+    assert_eq!(leaves, 1);
+}
+
+#[test]
+fn dfuse_file_without_prefix_is_rejected() {
+    let file = with_suffix(&image(100), 0x1209, 0xdf11);
+
+    assert!(matches!(
+        DfuseFile::parse(&file),
+        Err(FileError::InvalidDfuse(_))
+    ));
+    let mut truncated = dfuse_image(&[(0, &[(0x0800_0000, &image(100))])]);
+    truncated.truncate(truncated.len() - 1);
+    let image_size = (truncated.len() as u32).to_le_bytes();
+    truncated[6..10].copy_from_slice(&image_size);
+    assert!(matches!(
+        DfuseFile::parse(&with_suffix(&truncated, 0x1209, 0xdf11)),
+        Err(FileError::InvalidDfuse("truncated file"))
+    ));
+}
+
+#[test]
+fn intel_hex_segments_are_written_at_their_address() {
+    let mut hex = hex_record(0x04, 0, &[0x08, 0x00]);
+    hex += &hex_record(0x00, 0x0000, &[0x11; 16]);
+    hex += &hex_record(0x00, 0x0010, &[0x22; 16]);
+    hex += &hex_record(0x00, 0x0800, &[0x33; 8]);
+    hex += &hex_record(0x05, 0, &[0x08, 0x00, 0x00, 0x00]);
+    hex += &hex_record(0x01, 0, &[]);
+    let firmware = SparseFirmware::parse_intel_hex(&hex).unwrap();
+    assert_eq!(firmware.segments().len(), 2);
+    assert_eq!(firmware.segments()[1].address, 0x0800_0800);
+
+    let device = SimulatedDevice::new(4096)
+        .with_flash(vec![0; 4096])
+        .with_transfer_size(64)
+        .with_dfu_version(0x011a)
+        .with_alt_settings(["@Internal Flash  /0x08000000/04*001Kg"]);
+    let handle = device.clone();
+    block_on(async {
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        let options = DownloadOptions::new().with_verify(true);
+        device.download_sparse(&firmware, &options).await
+    })
+    .unwrap();
+
+    let flash = handle.flash();
+    assert_eq!(&flash[..16], &[0x11; 16]);
+    assert_eq!(&flash[16..32], &[0x22; 16]);
+    assert!(flash[32..0x400].iter().all(|&b| b == 0xff));
+    // The page between the segments is not erased.
+    assert!(flash[0x400..0x800].iter().all(|&b| b == 0));
+    assert_eq!(&flash[0x800..0x808], &[0x33; 8]);
+    assert!(flash[0x808..0xc00].iter().all(|&b| b == 0xff));
+    assert!(flash[0xc00..].iter().all(|&b| b == 0));
+}
+
+#[test]
+fn srec_gaps_are_refused_or_filled_for_plain_dfu() {
+    let mut srec = String::from("S00600004844521B\n");
+    srec += &srec_record(0x100, &[0xaa; 4]);
+    srec += &srec_record(0x108, &[0xbb; 4]);
+    srec += "S70500000000FA\n";
+    let firmware = SparseFirmware::parse_srec(&srec).unwrap();
+
+    let device = SimulatedDevice::new(1024).with_transfer_size(64);
+    let handle = device.clone();
+    block_on(async {
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        let refused = device
+            .download_sparse(&firmware, &DownloadOptions::new())
+            .await;
+        assert!(matches!(
+            refused,
+            Err(Error::File(FileError::Gap {
+                start: 0x104,
+                end: 0x108
+            }))
+        ));
+
+        let options = DownloadOptions::new().with_gap_fill(GapFill::Fill(0));
+        device.download_sparse(&firmware, &options).await
+    })
+    .unwrap();
+
+    assert_eq!(
+        &handle.flash()[..13],
+        &[
+            0xaa, 0xaa, 0xaa, 0xaa, 0, 0, 0, 0, 0xbb, 0xbb, 0xbb, 0xbb, 0xff
+        ]
+    );
+}
+
+#[test]
+fn corrupted_records_are_rejected() {
+    let mut hex = hex_record(0x00, 0, &[0x11; 4]);
+    hex.replace_range(9..11, "12");
+    hex += &hex_record(0x01, 0, &[]);
+    assert!(matches!(
+        SparseFirmware::parse_intel_hex(&hex),
+        Err(FileError::InvalidRecord {
+            line: 1,
+            reason: "checksum mismatch"
+        })
+    ));
+
+    let srec = srec_record(0, &[0x11; 4]);
+    assert!(matches!(
+        SparseFirmware::parse_srec(&srec),
+        Err(FileError::InvalidRecord {
+            reason: "missing termination record",
+            ..
+        })
+    ));
+
+    let overlapping = srec_record(0, &[0x11; 4]) + &srec_record(2, &[0x22; 4]) + "S70500000000FA\n";
+    assert!(matches!(
+        SparseFirmware::parse_srec(&overlapping),
+        Err(FileError::Overlap { address: 2 })
+    ));
+}
+
+#[test]
+fn elf_segments_are_written_at_their_load_address() {
+    let text = image(256);
+    let data = [0x42; 16];
+    let elf = elf32(&[
+        (".text", 0x0800_0000, 0x0800_0000, &text),
+        (".data", 0x2000_0000, 0x0800_0100, &data),
+    ]);
+    let firmware = ElfFirmware::parse(&elf).unwrap();
+    assert_eq!(firmware.sections()[1].name, ".data");
+    assert_eq!(firmware.sections()[1].address, 0x0800_0100);
+
+    let device = SimulatedDevice::new(2048)
+        .with_transfer_size(128)
+        .with_dfu_version(0x011a)
+        .with_alt_settings(["@Internal Flash  /0x08000000/02*001Kg"]);
+    let handle = device.clone();
+    block_on(async {
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        let options = DownloadOptions::new().with_verify(true);
+        device.download_elf(&firmware, &options).await
+    })
+    .unwrap();
+
+    let flash = handle.flash();
+    assert_eq!(&flash[..256], &text[..]);
+    assert_eq!(&flash[256..272], &data[..]);
+}
+
+#[test]
+fn elf_section_outside_writable_memory_is_named() {
+    let elf = elf32(&[
+        (".text", 0x0800_0000, 0x0800_0000, &image(256)),
+        (".rodata", 0x0800_0100, 0x0800_0100, &[0x42; 16]),
+    ]);
+    let firmware = ElfFirmware::parse(&elf).unwrap();
+    let device = SimulatedDevice::new(512)
+        .with_dfu_version(0x011a)
+        .with_alt_settings(["@Internal Flash  /0x08000000/01*256 g,01*256 a"]);
+    let handle = device.clone();
+
+    let result = block_on(async {
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        device
+            .download_elf(&firmware, &DownloadOptions::new())
+            .await
+    });
+
+    match result {
+        Err(Error::NotWritable { section, address }) => {
+            assert_eq!(section, ".rodata");
+            assert_eq!(address, 0x0800_0100);
+        }
+        other => panic!("unexpected result {other:?}"),
+    }
+    assert!(handle.flash().iter().all(|&b| b == 0xff));
+    assert!(matches!(
+        ElfFirmware::parse(&elf[..100]),
+        Err(FileError::InvalidElf("truncated file"))
+    ));
+}
+
+#[test]
+fn cancelled_download_stops_after_current_block() {
+    let device = SimulatedDevice::new(4096).with_transfer_size(256);
+    let handle = device.clone();
+
+    let result = block_on(async {
+        let mut device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        let cancel = device.cancel_handle();
+        device.with_progress(move |progress| {
+            if progress.phase == Phase::Write && progress.bytes >= 512 {
+                cancel.cancel();
+            }
+        });
+        let cancel = device.cancel_handle();
+        let mut dfu = device.into_async_dfu();
+        let result = dfu.download_from_slice(&[0x55; 2048]).await;
+        assert!(!cancel.is_cancelled());
+        Ok::<_, Error>(result)
+    })
+    .unwrap();
+
+    assert!(matches!(result, Err(Error::Aborted)));
+    assert_eq!(handle.state(), State::DfuIdle);
+    let flash = handle.flash();
+    assert_eq!(&flash[..512], &[0x55; 512]);
+    assert!(flash[512..].iter().all(|&b| b == 0xff));
+}
+
+#[test]
+fn cancelled_download_does_not_wait_for_stuck_abort() {
+    let device = SimulatedDevice::new(4096).with_transfer_size(256);
+    let handle = device.clone();
+
+    let result = block_on(async {
+        let mut device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        device.with_transfer_timeout(Duration::from_millis(50));
+        let cancel = device.cancel_handle();
+        device.with_progress(move |progress| {
+            if progress.phase == Phase::Write && progress.bytes >= 512 {
+                handle.hang_on(6);
+                cancel.cancel();
+            }
+        });
+        Ok::<_, Error>(
+            device
+                .download(&[0x55; 2048], &DownloadOptions::new())
+                .await,
+        )
+    })
+    .unwrap();
+
+    assert!(matches!(result, Err(Error::Aborted)), "{result:?}");
+}
+
+#[test]
+fn cancel_after_finished_download_does_not_stop_next_one() {
+    let device = SimulatedDevice::new(4096).with_transfer_size(256);
+    let handle = device.clone();
+
+    block_on(async {
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        let cancel = device.cancel_handle();
+        device
+            .download(&[0x55; 512], &DownloadOptions::new())
+            .await?;
+        cancel.cancel();
+        device
+            .download(&[0x66; 512], &DownloadOptions::new())
+            .await?;
+        cancel.cancel();
+        let mut dfu = device.into_async_dfu();
+        dfu.download_from_slice(&[0x77; 512]).await?;
+        assert!(!cancel.is_cancelled());
+        Ok::<_, Error>(())
+    })
+    .unwrap();
+
+    assert_eq!(&handle.flash()[..512], &[0x77; 512]);
+}
+
+#[test]
+fn stuck_request_times_out_with_its_request_code() {
+    let device = SimulatedDevice::new(1024).with_transfer_size(256);
+    device.hang_on(3);
+
+    let result = block_on(async {
+        let mut device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        device.with_transfer_timeout(Duration::from_millis(50));
+        device.download(&[0x55; 512], &DownloadOptions::new()).await
+    });
+
+    assert!(matches!(
+        result,
+        Err(Error::Timeout {
+            request: DfuRequest::GetStatus
+        })
+    ));
+}
+
+#[test]
+fn stuck_set_interface_and_reset_time_out() {
+    let device = SimulatedDevice::new(1024)
+        .with_runtime_mode()
+        .with_alt_settings(["Runtime", "Other"]);
+    let handle = device.clone();
+
+    let (set_alt_setting, detach) = block_on(async {
+        let mut device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        device.with_transfer_timeout(Duration::from_millis(50));
+        handle.hang_on_standard(usb::standard_request::SET_INTERFACE);
+        let set_alt_setting = device.set_alt_setting(1).await;
+        handle.hang_on_reset();
+        let detach = device.detach().await;
+        Ok::<_, Error>((set_alt_setting, detach))
+    })
+    .unwrap();
+
+    assert!(matches!(
+        set_alt_setting,
+        Err(Error::Timeout {
+            request: DfuRequest::SetInterface
+        })
+    ));
+    assert!(matches!(
+        detach,
+        Err(Error::Timeout {
+            request: DfuRequest::UsbReset
+        })
+    ));
+}
+
+#[test]
+fn manifestation_has_its_own_timeout() {
+    let device = SimulatedDevice::new(1024)
+        .with_transfer_size(256)
+        .with_poll_timeout(20)
+        .with_manifestation_polls(1000);
+
+    let result = block_on(async {
+        let mut device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        device.with_manifestation_timeout(Duration::from_millis(100));
+        device.download(&[0x55; 256], &DownloadOptions::new()).await
+    });
+
+    assert!(matches!(
+        result,
+        Err(Error::Timeout {
+            request: DfuRequest::GetStatus
+        })
+    ));
+}
+
+#[test]
+fn manifestation_timeout_bounds_async_dfu() {
+    let device = SimulatedDevice::new(1024)
+        .with_transfer_size(256)
+        .with_poll_timeout(20)
+        .with_manifestation_polls(300);
+
+    let result = block_on(async {
+        let mut device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        device.with_manifestation_timeout(Duration::from_millis(100));
+        let mut dfu = device.into_async_dfu();
+        Ok::<_, Error>(dfu.download_from_slice(&[0x55; 256]).await)
+    })
+    .unwrap();
+
+    assert!(
+        matches!(
+            result,
+            Err(Error::Timeout {
+                request: DfuRequest::GetStatus
+            })
+        ),
+        "{result:?}"
+    );
+}
+
+#[test]
+fn prepare_clears_error_left_by_previous_session() {
+    let device = SimulatedDevice::new(1024).with_state(State::DfuError, Status::ErrWrite);
+
+    let (recovery, device) = block_on(async {
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        let recovery = device.prepare().await?;
+        device
+            .download(&[0x55; 256], &DownloadOptions::new())
+            .await?;
+        Ok::<_, Error>((recovery, device))
+    })
+    .unwrap();
+
+    assert_eq!(recovery.state, State::DfuError);
+    assert_eq!(recovery.status, Status::ErrWrite);
+    assert_eq!(recovery.actions, [RecoveryAction::ClearStatus]);
+    assert_eq!(&device.transport().flash()[..256], &[0x55; 256]);
+}
+
+#[test]
+fn prepare_aborts_unfinished_download_and_leaves_idle_device_alone() {
+    let device = SimulatedDevice::new(1024).with_state(State::DfuDnloadIdle, Status::Ok);
+
+    let (aborted, idle) = block_on(async {
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        Ok::<_, Error>((device.prepare().await?, device.prepare().await?))
+    })
+    .unwrap();
+
+    assert_eq!(aborted.actions, [RecoveryAction::Abort]);
+    assert!(aborted.recovered());
+    assert_eq!(idle.state, State::DfuIdle);
+    assert!(!idle.recovered());
+}
+
+#[test]
+fn dfuse_command_failing_after_busy_polls_names_the_command() {
+    let device = SimulatedDevice::new(4096)
+        .with_dfu_version(0x011a)
+        .with_alt_settings(["@Internal Flash  /0x08000000/04*001Kg"])
+        .with_busy_polls(1);
+
+    let result = block_on(async {
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        Ok::<_, Error>(device.dfuse()?.erase_page(0x0800_1000).await)
+    })
+    .unwrap();
+
+    let Err(Error::DfuseCommand { command, source }) = result else {
+        panic!("expected a failed erase, got {result:?}");
+    };
+    assert_eq!(command, DfuseCommand::Erase(0x0800_1000));
+    assert_eq!(source.status, Status::ErrAddress);
+    assert_eq!(source.block, Some(0));
+}
+
+#[test]
+fn dfuse_commands_erase_pages_and_write_at_address() {
+    let device = SimulatedDevice::new(4096)
+        .with_flash(vec![0; 4096])
+        .with_transfer_size(256)
+        .with_dfu_version(0x011a)
+        .with_alt_settings(["@Internal Flash  /0x08000000/04*001Kg"]);
+    let handle = device.clone();
+
+    let result = block_on(async {
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        let dfuse = device.dfuse()?;
+        dfuse.erase_page(0x0800_0400).await?;
+        dfuse.set_address(0x0800_0400).await?;
+        DfuAsyncIo::write_control(&device, 0x21, 1, 2, &[0x55; 256]).await?;
+        DfuAsyncIo::read_control(&device, 0xa1, 3, 0, &mut [0; 6]).await?;
+        dfuse.abort().await?;
+        Ok::<_, Error>(dfuse.erase_page(0x0800_1000).await)
+    })
+    .unwrap();
+
+    let flash = handle.flash();
+    assert!(flash[..0x400].iter().all(|&b| b == 0));
+    assert_eq!(&flash[0x400..0x500], &[0x55; 256]);
+    assert!(flash[0x500..0x800].iter().all(|&b| b == 0xff));
+    let Err(Error::DfuseCommand { command, source }) = result else {
+        panic!("expected a failed erase, got {result:?}");
+    };
+    assert_eq!(command, DfuseCommand::Erase(0x0800_1000));
+    assert_eq!(source.status, Status::ErrAddress);
+}
+
+#[test]
+fn dfuse_read_unprotect_mass_erases_protected_device() {
+    let device = SimulatedDevice::new(2048)
+        .with_flash(vec![0; 2048])
+        .with_dfu_version(0x011a)
+        .with_alt_settings(["@Internal Flash  /0x08000000/02*001Kg"])
+        .with_read_protection();
+    let handle = device.clone();
+
+    block_on(async {
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        let mut firmware = Vec::new();
+        let upload = device.upload(&mut firmware, &UploadOptions::new()).await;
+        assert!(upload.is_err());
+        device.prepare().await?;
+        device.dfuse()?.read_unprotect().await?;
+        Ok::<_, Error>(())
+    })
+    .unwrap();
+
+    assert!(!handle.is_read_protected());
+    assert!(handle.flash().iter().all(|&b| b == 0xff));
+}
+
+#[test]
+fn dfuse_read_unprotect_fails_when_command_stalls() {
+    let device = SimulatedDevice::new(2048)
+        .with_attributes(sim::CAN_UPLOAD)
+        .with_dfu_version(0x011a)
+        .with_alt_settings(["@Internal Flash  /0x08000000/02*001Kg"])
+        .with_read_protection();
+    let handle = device.clone();
+
+    let result = block_on(async {
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        device.dfuse()?.read_unprotect().await
+    });
+
+    assert!(matches!(result, Err(Error::WebUsb(_))), "{result:?}");
+    assert!(handle.is_read_protected());
+}
+
+#[test]
+fn dfuse_commands_are_refused_on_plain_dfu() {
+    let device = SimulatedDevice::new(1024);
+
+    let device = block_on(DfuCrossUsb::from_transport(device, 0, 0)).unwrap();
+    assert!(matches!(device.dfuse(), Err(Error::DfuseNotSupported)));
+}
+
+#[test]
+fn flash_plan_erases_shared_pages_once_and_survives_serialization() {
+    let firmware = SparseFirmware::from_segments(vec![
+        Segment {
+            address: 0x0800_0000,
+            data: vec![0x11; 16],
+        },
+        Segment {
+            address: 0x0800_0100,
+            data: vec![0x22; 0x400],
+        },
+    ])
+    .unwrap();
+    let device = SimulatedDevice::new(4096)
+        .with_flash(vec![0; 4096])
+        .with_transfer_size(256)
+        .with_dfu_version(0x011a)
+        .with_alt_settings(["@Internal Flash  /0x08000000/04*001Kg"]);
+    let handle = device.clone();
+
+    let plan = block_on(async {
+        let mut device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        let plan = device.plan(&firmware)?;
+        assert_eq!(
+            plan.erases(),
+            [
+                ErasePage {
+                    address: 0x0800_0000,
+                    size: 0x400
+                },
+                ErasePage {
+                    address: 0x0800_0400,
+                    size: 0x400
+                },
+            ]
+        );
+        assert_eq!(plan.summary(), "erasing 2 pages (2 KiB), writing 2 KiB");
+
+        let parsed: FlashPlan = plan.to_string().parse()?;
+        assert_eq!(parsed, plan);
+        let options = DownloadOptions::new().with_verify(true);
+        device.execute_plan(&parsed, &options).await?;
+        Ok::<_, Error>(plan)
+    })
+    .unwrap();
+
+    let flash = handle.flash();
+    assert_eq!(&flash[..16], &[0x11; 16]);
+    assert!(flash[16..0x100].iter().all(|&b| b == 0xff));
+    assert!(flash[0x100..0x500].iter().all(|&b| b == 0x22));
+    assert!(flash[0x500..0x800].iter().all(|&b| b == 0xff));
+    assert!(flash[0x800..].iter().all(|&b| b == 0));
+    assert_eq!(plan.firmware(), &firmware);
+}
+
+#[test]
+fn flash_plan_rejects_malformed_lines() {
+    let plan = "dfu-cross-usb flash plan\nalt 0\nerase 0x08000000\n";
+    assert!(matches!(
+        plan.parse::<FlashPlan>(),
+        Err(FileError::InvalidRecord { line: 3, .. })
+    ));
+    assert!(matches!(
+        "alt 0\n".parse::<FlashPlan>(),
+        Err(FileError::InvalidRecord { line: 1, .. })
+    ));
+}
+
+#[test]
+fn flash_plan_not_matching_memory_layout_erases_nothing() {
+    let device = SimulatedDevice::new(4096)
+        .with_flash(vec![0; 4096])
+        .with_dfu_version(0x011a)
+        .with_alt_settings(["@Internal Flash  /0x08000000/04*001Kg"]);
+    let handle = device.clone();
+    let plan = |lines: &str| {
+        format!("dfu-cross-usb flash plan\nalt 0\nerase 0x08000000 0x400\n{lines}")
+            .parse::<FlashPlan>()
+            .unwrap()
+    };
+
+    block_on(async {
+        let mut device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        let options = DownloadOptions::new();
+        let outside = device
+            .execute_plan(&plan("write 0x20000000 11223344\n"), &options)
+            .await;
+        assert!(
+            matches!(outside, Err(Error::AddressOutOfRange { .. })),
+            "{outside:?}"
+        );
+        let not_erased = device
+            .execute_plan(&plan("write 0x080003fe 11223344\n"), &options)
+            .await;
+        assert!(
+            matches!(
+                not_erased,
+                Err(Error::PlanMismatch {
+                    address: 0x0800_0400
+                })
+            ),
+            "{not_erased:?}"
+        );
+        let wrong_size = device
+            .execute_plan(&plan("erase 0x08000400 0x800\n"), &options)
+            .await;
+        assert!(
+            matches!(
+                wrong_size,
+                Err(Error::PlanMismatch {
+                    address: 0x0800_0400
+                })
+            ),
+            "{wrong_size:?}"
+        );
+        Ok::<_, Error>(())
+    })
+    .unwrap();
+
+    assert!(handle.flash().iter().all(|&b| b == 0));
+    assert!(matches!(
+        "dfu-cross-usb flash plan\nalt 0\nerase 0x08000000 0xffffffff\n".parse::<FlashPlan>(),
+        Err(FileError::InvalidRecord { line: 3, .. })
+    ));
+}
+
+fn dfuse_firmware(data: &[u8]) -> SparseFirmware {
+    SparseFirmware::from_segments(vec![Segment {
+        address: 0x0800_0000,
+        data: data.to_vec(),
+    }])
+    .unwrap()
+}
+
+#[test]
+fn diff_plan_only_rewrites_pages_that_differ_from_the_device() {
+    let old = image(3000);
+    let mut new = old.clone();
+    new[1500] ^= 0xff;
+    let mut flash = old.clone();
+    flash.resize(4096, 0xff);
+    let device = SimulatedDevice::new(4096)
+        .with_flash(flash)
+        .with_transfer_size(256)
+        .with_dfu_version(0x011a)
+        .with_alt_settings(["@Internal Flash  /0x08000000/04*001Kg"]);
+    let handle = device.clone();
+
+    let diff = block_on(async {
+        let mut device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        let plan = device.plan(&dfuse_firmware(&new))?;
+        let diff = device.diff_plan(&plan).await?;
+        device.execute_plan(&diff, &DownloadOptions::new()).await?;
+        Ok::<_, Error>(diff)
+    })
+    .unwrap();
+
+    assert_eq!(
+        diff.erases(),
+        [ErasePage {
+            address: 0x0800_0400,
+            size: 0x400
+        }]
+    );
+    assert_eq!(diff.write_bytes(), 0x400);
+    assert_eq!(&handle.flash()[..new.len()], &new[..]);
+}
+
+#[test]
+fn page_hashes_of_previous_plan_skip_unchanged_pages() {
+    let old = image(3000);
+    let mut new = old.clone();
+    new[2500] ^= 0xff;
+    let device = SimulatedDevice::new(4096)
+        .with_dfu_version(0x011a)
+        .with_alt_settings(["@Internal Flash  /0x08000000/04*001Kg"]);
+
+    let (old_plan, new_plan) = block_on(async {
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        let old_plan = device.plan(&dfuse_firmware(&old))?;
+        let new_plan = device.plan(&dfuse_firmware(&new))?;
+        Ok::<_, Error>((old_plan, new_plan))
+    })
+    .unwrap();
+
+    let hashes: Vec<PageHash> = old_plan.page_hashes();
+    assert_eq!(hashes.len(), 3);
+    let diff = new_plan.without_unchanged(&hashes);
+    assert_eq!(diff.erases().len(), 1);
+    assert_eq!(diff.erases()[0].address, 0x0800_0800);
+    assert_eq!(diff.firmware().segments()[0].data, &new[0x800..]);
+
+    let unchanged = old_plan.without_unchanged(&hashes);
+    assert!(unchanged.erases().is_empty());
+    assert!(unchanged.firmware().is_empty());
+    assert!(unchanged.to_string().contains("start 0x08000000"));
+}
+
+#[test]
+fn failed_download_restores_backup() {
+    let old = image(1024);
+    let device = SimulatedDevice::new(0)
+        .with_flash(old.clone())
+        .with_transfer_size(256);
+    let handle = device.clone();
+    handle.fail_block(2, Status::ErrProg);
+
+    let report = block_on(async {
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        device
+            .download_with_backup(&[0x55; 1024], &DownloadOptions::new())
+            .await
+    })
+    .unwrap();
+
+    assert_eq!(report.address, None);
+    assert_eq!(report.backup, old);
+    assert!(matches!(report.failure, Some(Error::Device(_))));
+    assert!(report.restored());
+    assert_eq!(handle.flash(), old);
+}
+
+#[test]
+fn dfuse_backup_covers_erased_pages() {
+    let old = image(4096);
+    let device = SimulatedDevice::new(0)
+        .with_flash(old.clone())
+        .with_transfer_size(256)
+        .with_dfu_version(0x011a)
+        .with_alt_settings(["@Internal Flash  /0x08000000/04*001Kg"]);
+    let handle = device.clone();
+
+    let report = block_on(async {
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        let options = DownloadOptions::new()
+            .with_address(0x0800_0500)
+            .with_verify(true);
+        device.download_with_backup(&[0x55; 0x400], &options).await
+    })
+    .unwrap();
+
+    assert!(report.succeeded());
+    assert!(report.restore.is_none());
+    assert_eq!(report.address, Some(0x0800_0400));
+    assert_eq!(report.backup, &old[0x400..0xc00]);
+    assert_eq!(&handle.flash()[0x500..0x900], &[0x55; 0x400]);
+}
+
+#[test]
+fn dfuse_restore_leaves_from_download_address() {
+    let old = image(4096);
+    let device = SimulatedDevice::new(0)
+        .with_flash(old.clone())
+        .with_transfer_size(256)
+        .with_dfu_version(0x011a)
+        .with_alt_settings(["@Internal Flash  /0x08000000/04*001Kg"]);
+    let handle = device.clone();
+    // The first block of the download, after the set address command.
+    handle.fail_block(2, Status::ErrProg);
+
+    let report = block_on(async {
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        let options = DownloadOptions::new().with_address(0x0800_0500);
+        device.download_with_backup(&[0x55; 0x400], &options).await
+    })
+    .unwrap();
+
+    assert!(matches!(report.failure, Some(Error::Device(_))));
+    assert!(report.restored());
+    assert_eq!(report.address, Some(0x0800_0400));
+    assert_eq!(handle.flash(), old);
+    assert_eq!(handle.address(), 0x0800_0500);
+}
+
+#[test]
+fn backup_is_refused_without_upload_support() {
+    let device = SimulatedDevice::new(1024).with_attributes(sim::CAN_DOWNLOAD);
+
+    let result = block_on(async {
+        let device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        device
+            .download_with_backup(&[0x55; 16], &DownloadOptions::new())
+            .await
+    });
+
+    assert!(matches!(result, Err(Error::UploadNotSupported)));
+}
+
+/// The option bytes of an STM32F1 as shipped: level 0, no write protection.
+const F1_OPTION_BYTES: [u8; 16] = [
+    0xa5, 0x5a, 0x07, 0xf8, 0x00, 0xff, 0x00, 0xff, 0xff, 0x00, 0xff, 0x00, 0xff, 0x00, 0xff, 0x00,
+];
+
+fn stm32(option_bytes: &str, bytes: Vec<u8>) -> SimulatedDevice {
+    SimulatedDevice::new(2048)
+        .with_dfu_version(0x011a)
+        .with_alt_settings(["@Internal Flash  /0x08000000/02*001Kg", option_bytes])
+        .with_memory(1, bytes)
+}
+
+#[test]
+fn option_bytes_are_decoded_and_written_back() {
+    let device = stm32(
+        "@Option Bytes  /0x1FFFF800/01*016 e",
+        F1_OPTION_BYTES.to_vec(),
+    );
+    let handle = device.clone();
+
+    let (read, written) = block_on(async {
+        let mut device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        let mut bytes = device.read_option_bytes(Stm32Family::F1).await?;
+        assert_eq!(device.alt_setting(), 0);
+        let read = bytes.clone();
+        bytes.set_read_protection(ReadProtection::Level1)?;
+        bytes.set_write_protection(0b11);
+        bytes.set_user(UserOptions {
+            software_watchdog: false,
+            reset_on_stop: false,
+            reset_on_standby: true,
+        });
+        let write = device.write_option_bytes(bytes);
+        assert!(!write.is_irreversible());
+        let written = write.option_bytes().clone();
+        write.confirm().await?;
+        Ok::<_, Error>((read, written))
+    })
+    .unwrap();
+
+    assert_eq!(read.read_protection(), ReadProtection::Level0);
+    assert_eq!(read.write_protection(), 0);
+    assert_eq!(
+        read.user(),
+        UserOptions {
+            software_watchdog: true,
+            reset_on_stop: false,
+            reset_on_standby: false,
+        }
+    );
+    let memory = handle.memory(1);
+    assert_eq!(memory, written.as_bytes());
+    let decoded = OptionBytes::decode(Stm32Family::F1, 0x1fff_f800, &memory).unwrap();
+    assert_eq!(decoded.read_protection(), ReadProtection::Level1);
+    assert_eq!(decoded.write_protection(), 0b11);
+    assert_eq!(&memory[..4], &[0x00, 0xff, 0x02, 0xfd]);
+}
+
+#[test]
+fn read_protection_level_2_needs_irreversible_confirmation() {
+    let mut f4 = vec![0xff; 16];
+    f4[0] = 0xec;
+    f4[1] = 0xaa;
+    let device = stm32("@Option Bytes  /0x1FFFC000/01*016 e", f4.clone());
+    let handle = device.clone();
+
+    let result = block_on(async {
+        let mut device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        let mut bytes = device.read_option_bytes(Stm32Family::F4).await?;
+        assert_eq!(bytes.read_protection(), ReadProtection::Level0);
+        bytes.set_read_protection(ReadProtection::Level2)?;
+        let write = device.write_option_bytes(bytes);
+        assert!(write.is_irreversible());
+        Ok::<_, Error>(write.confirm().await)
+    })
+    .unwrap();
+
+    assert!(matches!(
+        result,
+        Err(Error::OptionBytes(OptionBytesError::Irreversible))
+    ));
+    assert_eq!(handle.memory(1), f4);
+}
+
+#[test]
+fn option_bytes_write_fails_when_set_address_stalls() {
+    let device = stm32(
+        "@Option Bytes  /0x1FFFF800/01*016 e",
+        F1_OPTION_BYTES.to_vec(),
+    )
+    .with_attributes(sim::CAN_UPLOAD);
+    let handle = device.clone();
+
+    let result = block_on(async {
+        let mut device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        let mut bytes = OptionBytes::decode(Stm32Family::F1, 0x1fff_f800, &F1_OPTION_BYTES)?;
+        bytes.set_read_protection(ReadProtection::Level1)?;
+        Ok::<_, Error>(device.write_option_bytes(bytes).confirm().await)
+    })
+    .unwrap();
+
+    assert!(matches!(result, Err(Error::WebUsb(_))), "{result:?}");
+    assert_eq!(handle.memory(1), F1_OPTION_BYTES);
+}
+
+#[test]
+fn malformed_option_bytes_and_unsupported_families_are_refused() {
+    assert!(matches!(
+        "STM32L476RG".parse::<Stm32Family>(),
+        Err(OptionBytesError::UnsupportedFamily(_))
+    ));
+    assert_eq!(
+        "stm32f103c8".parse::<Stm32Family>().unwrap(),
+        Stm32Family::F1
+    );
+    assert!(matches!(
+        OptionBytes::decode(Stm32Family::F4, 0x1fff_f800, &F1_OPTION_BYTES),
+        Err(OptionBytesError::UnexpectedAddress { .. })
+    ));
+    assert!(matches!(
+        OptionBytes::decode(Stm32Family::F1, 0x1fff_f800, &F1_OPTION_BYTES[..8]),
+        Err(OptionBytesError::InvalidLength { actual: 8, .. })
+    ));
+    let mut corrupted = F1_OPTION_BYTES;
+    corrupted[3] = 0;
+    assert!(matches!(
+        OptionBytes::decode(Stm32Family::F1, 0x1fff_f800, &corrupted),
+        Err(OptionBytesError::ComplementMismatch { offset: 2 })
+    ));
+    let mut f1 = OptionBytes::decode(Stm32Family::F1, 0x1fff_f800, &F1_OPTION_BYTES).unwrap();
+    assert!(matches!(
+        f1.set_read_protection(ReadProtection::Level2),
+        Err(OptionBytesError::UnsupportedLevel { .. })
+    ));
+
+    let device = SimulatedDevice::new(1024);
+    let result = block_on(async {
+        let mut device = DfuCrossUsb::from_transport(device, 0, 0).await?;
+        device.read_option_bytes(Stm32Family::F1).await
+    });
+    assert!(matches!(
+        result,
+        Err(Error::OptionBytes(OptionBytesError::NotFound))
+    ));
+}